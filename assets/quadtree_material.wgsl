@@ -0,0 +1,72 @@
+// Morphs a quadtree patch towards the next coarser level: each vertex
+// slides from its own position, normal and colour to the ones it has on the
+// coarser grid as the camera moves through the patch level's morph range.
+// The fragment stage is the standard material's.
+
+#import bevy_pbr::{
+    mesh_functions,
+    forward_io::VertexOutput,
+    view_transformations::position_world_to_clip,
+}
+
+// camera distances the morph starts and ends at in x and y, both zero for
+// patches that don't morph
+@group(2) @binding(100)
+var<uniform> morph_range: vec4<f32>;
+// world position of the camera the morph follows in xyz
+@group(2) @binding(101)
+var<uniform> camera: vec4<f32>;
+
+struct Vertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(5) color: vec4<f32>,
+    @location(10) morph_position: vec3<f32>,
+    @location(11) morph_normal: vec3<f32>,
+    @location(12) morph_color: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    let world_from_local = mesh_functions::get_world_from_local(vertex.instance_index);
+    let own = mesh_functions::mesh_position_local_to_world(
+        world_from_local,
+        vec4<f32>(vertex.position, 1.0),
+    );
+    let morphed = mesh_functions::mesh_position_local_to_world(
+        world_from_local,
+        vec4<f32>(vertex.morph_position, 1.0),
+    );
+
+    // by the camera's distance to the vertex at its own level
+    var k = 0.0;
+    if morph_range.y > morph_range.x {
+        let camera_distance = distance(camera.xyz, own.xyz);
+        k = clamp((camera_distance - morph_range.x) / (morph_range.y - morph_range.x), 0.0, 1.0);
+    }
+
+    out.world_position = mix(own, morphed, k);
+    out.position = position_world_to_clip(out.world_position.xyz);
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(
+        normalize(mix(vertex.normal, vertex.morph_normal, k)),
+        vertex.instance_index,
+    );
+
+#ifdef VERTEX_COLORS
+    out.color = mix(vertex.color, vertex.morph_color, k);
+#endif
+
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+
+#ifdef VISIBILITY_RANGE_DITHER
+    out.visibility_range_dither = mesh_functions::get_visibility_range_dither_level(
+        vertex.instance_index, world_from_local[3]);
+#endif
+
+    return out;
+}
@@ -0,0 +1,74 @@
+// Displaces the shared flat chunk grid by a chunk's height texture. The
+// fragment stage is the standard material's, with the colour texture as its
+// base colour.
+
+#import bevy_pbr::{
+    mesh_functions,
+    forward_io::{Vertex, VertexOutput},
+    view_transformations::position_world_to_clip,
+}
+
+// world (x, z) of the first texel in xy, world units between texels in zw
+@group(2) @binding(100)
+var<uniform> area: vec4<f32>;
+@group(2) @binding(101)
+var heights: texture_2d<f32>;
+
+fn texel_height(texel: vec2<i32>) -> f32 {
+    let last = vec2<i32>(textureDimensions(heights)) - vec2<i32>(1, 1);
+    return textureLoad(heights, clamp(texel, vec2<i32>(0, 0), last), 0).r;
+}
+
+// height at fractional texel coordinates, interpolated like
+// `Heightfield::bilinear`
+fn height_at(texel_position: vec2<f32>) -> f32 {
+    let cell = floor(texel_position);
+    let offset = texel_position - cell;
+    let texel = vec2<i32>(cell);
+    let top = mix(texel_height(texel), texel_height(texel + vec2<i32>(1, 0)), offset.x);
+    let bottom = mix(
+        texel_height(texel + vec2<i32>(0, 1)),
+        texel_height(texel + vec2<i32>(1, 1)),
+        offset.x,
+    );
+    return mix(top, bottom, offset.y);
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+
+    let world_from_local = mesh_functions::get_world_from_local(vertex.instance_index);
+    let grid_position = mesh_functions::mesh_position_local_to_world(
+        world_from_local,
+        vec4<f32>(vertex.position, 1.0),
+    );
+    let texel_position = (grid_position.xz - area.xy) / area.zw;
+
+    // central differences one texel either side, like `sample_normal`
+    let step_x = vec2<f32>(1.0, 0.0);
+    let step_z = vec2<f32>(0.0, 1.0);
+    let dx = (height_at(texel_position + step_x) - height_at(texel_position - step_x)) / (2.0 * area.z);
+    let dz = (height_at(texel_position + step_z) - height_at(texel_position - step_z)) / (2.0 * area.w);
+
+    let height = height_at(texel_position);
+    out.world_position = vec4<f32>(grid_position.x, height, grid_position.z, 1.0);
+    out.position = position_world_to_clip(out.world_position.xyz);
+    out.world_normal = normalize(vec3<f32>(-dx, 1.0, -dz));
+
+#ifdef VERTEX_UVS_A
+    // the colour texture has a texel per height texel, centred on it
+    out.uv = (texel_position + vec2<f32>(0.5, 0.5)) / vec2<f32>(textureDimensions(heights));
+#endif
+
+#ifdef VERTEX_OUTPUT_INSTANCE_INDEX
+    out.instance_index = vertex.instance_index;
+#endif
+
+#ifdef VISIBILITY_RANGE_DITHER
+    out.visibility_range_dither = mesh_functions::get_visibility_range_dither_level(
+        vertex.instance_index, world_from_local[3]);
+#endif
+
+    return out;
+}
@@ -0,0 +1,292 @@
+use bevy::prelude::*;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use serde::Deserialize;
+use smallvec::{smallvec, SmallVec};
+
+use crate::noise_stack::derive_seed;
+use crate::palette::TerrainPalette;
+use crate::terrain_settings::TerrainSettings;
+
+/// A region of climate space with its own colours and terrain shape.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Biome {
+    pub name: String,
+    /// Climate this biome is centred on, both roughly `-1.0..=1.0`.
+    pub temperature: f32,
+    pub moisture: f32,
+    /// `TerrainPalette` asset path, relative to the asset folder.
+    pub palette: String,
+    /// Multiplies the noise height (in palette units) inside the biome.
+    pub height_scale: f32,
+    /// Added to the noise height (in palette units) inside the biome.
+    pub height_offset: f32,
+}
+
+impl Default for Biome {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            temperature: 0.0,
+            moisture: 0.0,
+            palette: String::new(),
+            height_scale: 1.0,
+            height_offset: 0.0,
+        }
+    }
+}
+
+/// Temperature and moisture fields that pick a biome for every point.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Biomes {
+    /// Empty colours the whole terrain with the settings' `palette`. The
+    /// default, so an unconfigured terrain looks and times like it did
+    /// before biomes; the shipped settings list four to uncomment.
+    pub list: Vec<Biome>,
+    /// Field frequencies relative to the base sampling scale.
+    pub temperature_frequency: f64,
+    pub moisture_frequency: f64,
+    /// Temperature lost per unit of height (palette units) above zero, so
+    /// peaks turn cold whatever the climate around them.
+    pub lapse_rate: f32,
+    /// Climate distance over which neighbouring biomes blend; `0.0` gives
+    /// hard borders.
+    pub blend: f32,
+}
+
+impl Default for Biomes {
+    fn default() -> Self {
+        Self {
+            list: Vec::new(),
+            temperature_frequency: 0.3,
+            moisture_frequency: 0.4,
+            lapse_rate: 0.3,
+            blend: 0.1,
+        }
+    }
+}
+
+/// Most regula falsi steps `modify_height` takes towards the height its
+/// climate is taken at; only points on steep biome borders need many.
+const CLIMATE_STEPS: usize = 16;
+/// World height within which `modify_height`'s guesses count as settled.
+const CLIMATE_TOLERANCE: f32 = 1e-3;
+
+/// Biomes sharing a point; more than this many spill onto the heap.
+const INLINE_WEIGHTS: usize = 8;
+
+pub struct BiomeMap {
+    biomes: Biomes,
+    temperature: Fbm<Perlin>,
+    moisture: Fbm<Perlin>,
+    scale: f64,
+    height_scale: f32,
+}
+
+impl BiomeMap {
+    /// `None` when no biomes are configured.
+    pub fn new(settings: &TerrainSettings) -> Option<Self> {
+        if settings.biomes.list.is_empty() {
+            return None;
+        }
+
+        // seeds below the warp fields' so the climate doesn't follow them
+        let field = |seed: u32, frequency: f64| {
+            Fbm::<Perlin>::new(seed)
+                .set_octaves(3)
+                .set_frequency(frequency)
+        };
+        Some(Self {
+            biomes: settings.biomes.clone(),
+            temperature: field(
+                derive_seed(settings.seed, -3),
+                settings.biomes.temperature_frequency,
+            ),
+            moisture: field(
+                derive_seed(settings.seed, -4),
+                settings.biomes.moisture_frequency,
+            ),
+            scale: settings.noise_scale,
+            height_scale: settings.height_scale,
+        })
+    }
+
+    pub fn biomes(&self) -> &[Biome] {
+        &self.biomes.list
+    }
+
+    /// (temperature, moisture) at world `(x, z)` and world `height`.
+    pub fn climate(&self, x: f64, z: f64, height: f32) -> Vec2 {
+        let point = [x / self.scale, z / self.scale, 0.];
+        let sea_level = Vec2::new(
+            self.temperature.get(point) as f32,
+            self.moisture.get(point) as f32,
+        );
+        self.lapse(sea_level, height)
+    }
+
+    /// `climate` at sea level cooled for world `height`.
+    fn lapse(&self, climate: Vec2, height: f32) -> Vec2 {
+        let g = height / self.height_scale * 2.;
+        climate - Vec2::X * g.max(0.0) * self.biomes.lapse_rate
+    }
+
+    /// Normalised weights of the biomes near `climate`. Every biome within
+    /// `blend` of the closest one gets a share, fading out with distance.
+    /// Called for every sample, so it doesn't allocate for the usual few.
+    pub fn weights(&self, climate: Vec2) -> SmallVec<[(usize, f32); INLINE_WEIGHTS]> {
+        let distance =
+            |biome: &Biome| climate.distance(Vec2::new(biome.temperature, biome.moisture));
+        let (closest, nearest) = self
+            .biomes
+            .list
+            .iter()
+            .map(distance)
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or_default();
+
+        if self.biomes.blend <= 0.0 {
+            return smallvec![(closest, 1.0)];
+        }
+
+        let mut weights: SmallVec<[(usize, f32); INLINE_WEIGHTS]> = self
+            .biomes
+            .list
+            .iter()
+            .map(distance)
+            .enumerate()
+            .filter_map(|(i, distance)| {
+                let t = 1.0 - (distance - nearest) / self.biomes.blend;
+                (t > 0.0).then_some((i, t * t * (3.0 - 2.0 * t)))
+            })
+            .collect();
+        let total: f32 = weights.iter().map(|(_, weight)| weight).sum();
+        for (_, weight) in weights.iter_mut() {
+            *weight /= total;
+        }
+        weights
+    }
+
+    /// The biome with the largest weight at world `(x, z)` and `height`.
+    pub fn dominant(&self, x: f64, z: f64, height: f32) -> usize {
+        self.weights(self.climate(x, z, height))
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// `height` reshaped by the height modifiers of the biomes at `(x, z)`.
+    /// The biomes are weighted by the climate at the reshaped height, which
+    /// is what `color` sees, so a point takes its shape and its colour from
+    /// the same biomes. Away from biome borders reshaping once settles it;
+    /// near them it's found by regula falsi.
+    pub fn modify_height(&self, x: f64, z: f64, height: f32) -> f32 {
+        let g = height / self.height_scale * 2.;
+        let sea_level = self.climate(x, z, 0.0);
+        let biome_height =
+            |biome: &Biome| (g * biome.height_scale + biome.height_offset) * self.height_scale / 2.;
+        let reshape = |climate_height: f32| -> f32 {
+            self.weights(self.lapse(sea_level, climate_height))
+                .into_iter()
+                .map(|(i, weight)| biome_height(&self.biomes.list[i]) * weight)
+                .sum()
+        };
+        // how far reshaping moves a height; zero at the one we're after
+        let offset = |h: f32| reshape(h) - h;
+
+        let guess = reshape(height);
+        let step = offset(guess);
+        if step.abs() <= CLIMATE_TOLERANCE {
+            return guess + step;
+        }
+
+        // a reshaped height is a blend of the biomes' own, so it can't rise
+        // above the highest of them: past the guess in the direction it
+        // moved, the offset changes sign
+        let (low, high) = self
+            .biomes
+            .list
+            .iter()
+            .map(biome_height)
+            .fold((f32::MAX, f32::MIN), |(low, high), h| {
+                (low.min(h), high.max(h))
+            });
+        let (mut a, mut b) = if step > 0.0 {
+            ((guess, step), (high, offset(high)))
+        } else {
+            ((low, offset(low)), (guess, step))
+        };
+        // Illinois variant: halve the end that keeps being kept, so it
+        // still converges quickly when the offset is lopsided
+        let mut kept = 0;
+        let mut best = guess;
+        for _ in 0..CLIMATE_STEPS {
+            if a.1 - b.1 <= f32::EPSILON {
+                break;
+            }
+            let h = (a.0 * b.1 - b.0 * a.1) / (b.1 - a.1);
+            let moved = offset(h);
+            best = h;
+            if moved.abs() <= CLIMATE_TOLERANCE {
+                return h + moved;
+            }
+            if moved > 0.0 {
+                a = (h, moved);
+                if kept == 1 {
+                    b.1 /= 2.0;
+                }
+                kept = 1;
+            } else {
+                b = (h, moved);
+                if kept == -1 {
+                    a.1 /= 2.0;
+                }
+                kept = -1;
+            }
+        }
+        reshape(best)
+    }
+
+    /// Blend of the biome palettes' colours at world `position`.
+    pub fn color(&self, palettes: &[TerrainPalette], position: Vec3, normal: Vec3) -> [f32; 4] {
+        let climate = self.climate(position.x as f64, position.z as f64, position.y);
+        let mut color = Vec4::ZERO;
+        for (i, weight) in self.weights(climate) {
+            let biome = Vec4::from_array(palettes[i].color(position.y, normal, self.height_scale));
+            color += biome * weight;
+        }
+        color.to_array()
+    }
+}
+
+/// Flat colour for biome `index` in the debug view, spread around the hue
+/// circle.
+pub fn debug_color(index: usize, count: usize) -> [f32; 4] {
+    let hue = index as f32 * 360.0 / count.max(1) as f32;
+    LinearRgba::from(Color::hsl(hue, 0.8, 0.5)).to_f32_array()
+}
+
+/// How vertices get their colour: the plain palette, or blended biome
+/// palettes when biomes are configured.
+pub struct TerrainColoring {
+    pub palette: TerrainPalette,
+    /// One palette per biome, in `map.biomes()` order.
+    pub biomes: Option<(BiomeMap, Vec<TerrainPalette>)>,
+    /// Colour each vertex by its dominant biome instead.
+    pub debug: bool,
+}
+
+impl TerrainColoring {
+    pub fn color(&self, position: Vec3, normal: Vec3, height_scale: f32) -> [f32; 4] {
+        match &self.biomes {
+            Some((map, _)) if self.debug => debug_color(
+                map.dominant(position.x as f64, position.z as f64, position.y),
+                map.biomes().len(),
+            ),
+            Some((map, palettes)) => map.color(palettes, position, normal),
+            None => self.palette.color(position.y, normal, height_scale),
+        }
+    }
+}
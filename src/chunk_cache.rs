@@ -0,0 +1,483 @@
+//! On-disk cache of generated chunks, keyed on the settings that shape
+//! them. Only heights and normals are stored, not vertex colours: those
+//! depend on the palette and biome palettes, which are edited live, so
+//! caching them would either go stale or throw away the whole cache on every
+//! palette edit. Colouring a chunk from its cached samples is cheap next to
+//! the noise and erosion the cache skips.
+
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+
+use crate::biome::TerrainColoring;
+use crate::heightfield::{TerrainRegion, CHUNK_VERTICES};
+use crate::normals;
+use crate::terrain_settings::{HeightSource, TerrainSettings};
+
+/// First bytes of every cache file.
+const MAGIC: &[u8; 4] = b"TCHK";
+/// Bumped whenever the layout below changes; older files are regenerated.
+const VERSION: u32 = 2;
+/// Magic, version, settings hash, chunk x and z, samples per edge.
+const HEADER_BYTES: usize = 4 + 4 + 8 + 4 + 4 + 4;
+/// Height (`f32`) and normal x and z (`i16` each) per sample.
+const SAMPLE_BYTES: usize = 4 + 2 + 2;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ChunkCacheSettings {
+    /// Reuse generated chunks from earlier runs with the same settings.
+    pub enabled: bool,
+    /// Folder the cache files go in, relative to the working directory.
+    pub dir: String,
+}
+
+impl Default for ChunkCacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: "target/chunk_cache".to_string(),
+        }
+    }
+}
+
+/// A chunk's generated samples, covering the chunk and one sample past it
+/// on every side, row by row: what GPU displacement uploads. The cache
+/// stores the heights and normals; colours follow the palette, so they're
+/// worked out again on load.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkSamples {
+    pub heights: Vec<f32>,
+    pub normals: Vec<Vec3>,
+    /// sRGB vertex colours.
+    pub colors: Vec<[u8; 4]>,
+}
+
+impl ChunkSamples {
+    /// Samples along each edge.
+    pub const SIZE: usize = CHUNK_VERTICES + 2;
+
+    pub fn new(
+        settings: &TerrainSettings,
+        region: &TerrainRegion,
+        coloring: &TerrainColoring,
+        chunk: IVec2,
+    ) -> Self {
+        let (first_x, first_z) = region.chunk_sample(chunk, 0, 0);
+        // the region always has at least one sample of apron
+        let (first_x, first_z) = (first_x - 1, first_z - 1);
+
+        let count = Self::SIZE * Self::SIZE;
+        let mut samples = Self {
+            heights: Vec::with_capacity(count),
+            normals: Vec::with_capacity(count),
+            colors: Vec::new(),
+        };
+        for z in first_z..first_z + Self::SIZE {
+            for x in first_x..first_x + Self::SIZE {
+                samples.heights.push(region.heights.get(x, z));
+                samples.normals.push(normals::sample_normal(region, x, z));
+            }
+        }
+        samples.color(settings, coloring, chunk);
+        samples
+    }
+
+    /// Fills in the colours from the heights and normals.
+    fn color(&mut self, settings: &TerrainSettings, coloring: &TerrainColoring, chunk: IVec2) {
+        let origin = Self::origin(chunk);
+        let spacing = TerrainRegion::spacing();
+        self.colors = (self.heights.iter().zip(&self.normals))
+            .enumerate()
+            .map(|(i, (height, normal))| {
+                let sample = Vec2::new((i % Self::SIZE) as f32, (i / Self::SIZE) as f32);
+                let world = origin + sample * spacing;
+                let color = coloring.color(
+                    Vec3::new(world.x, *height, world.y),
+                    *normal,
+                    settings.height_scale,
+                );
+                Color::LinearRgba(LinearRgba::from_f32_array(color))
+                    .to_srgba()
+                    .to_u8_array()
+            })
+            .collect();
+    }
+
+    /// World (x, z) of `chunk`'s first sample.
+    pub fn origin(chunk: IVec2) -> Vec2 {
+        TerrainRegion::new(chunk, chunk, 1)
+            .world_position(0, 0)
+            .as_vec2()
+    }
+}
+
+#[derive(Debug)]
+pub enum ChunkCacheError {
+    Io(std::io::Error),
+    BadMagic,
+    Version(u32),
+    /// Written for other settings or another chunk.
+    Stale,
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    Checksum,
+}
+
+impl fmt::Display for ChunkCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkCacheError::Io(err) => write!(f, "{err}"),
+            ChunkCacheError::BadMagic => write!(f, "not a chunk cache file"),
+            ChunkCacheError::Version(version) => {
+                write!(f, "cache format version {version}, expected {VERSION}")
+            }
+            ChunkCacheError::Stale => write!(f, "cached for other settings"),
+            ChunkCacheError::Truncated { expected, actual } => {
+                write!(f, "cache file is {actual} bytes, expected {expected}")
+            }
+            ChunkCacheError::Checksum => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for ChunkCacheError {}
+
+impl From<std::io::Error> for ChunkCacheError {
+    fn from(err: std::io::Error) -> Self {
+        ChunkCacheError::Io(err)
+    }
+}
+
+/// Generated chunks on disk, one file per chunk coordinate. Each file
+/// records the hash of the settings it was generated with, so changing them
+/// makes every entry stale; stale entries are overwritten as the chunks
+/// generate again.
+pub struct ChunkCache {
+    dir: PathBuf,
+    key: u64,
+}
+
+impl ChunkCache {
+    /// The settings' cache for the region from `min_chunk` to `max_chunk`,
+    /// or `None` if caching is off. Erosion runs over the whole region, so
+    /// its bounds are part of the key.
+    pub fn new(settings: &TerrainSettings, min_chunk: IVec2, max_chunk: IVec2) -> Option<Self> {
+        settings.cache.enabled.then(|| Self {
+            dir: PathBuf::from(&settings.cache.dir),
+            key: settings_key(settings, min_chunk, max_chunk),
+        })
+    }
+
+    fn path(&self, chunk: IVec2) -> PathBuf {
+        self.dir.join(format!("{}_{}.chunk", chunk.x, chunk.y))
+    }
+
+    /// The cached samples of `chunk`, without colours, or `None` if it
+    /// isn't cached.
+    pub fn load(&self, chunk: IVec2) -> Result<Option<ChunkSamples>, ChunkCacheError> {
+        match std::fs::read(self.path(chunk)) {
+            Ok(bytes) => decode(&bytes, self.key, chunk).map(Some),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes `chunk`'s samples, replacing any older entry. The file is
+    /// written beside its final name and renamed into place, so a run that
+    /// stops part way never leaves half an entry.
+    pub fn store(&self, chunk: IVec2, samples: &ChunkSamples) -> Result<(), ChunkCacheError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path(chunk);
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, encode(samples, self.key, chunk))?;
+        std::fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// Cached samples for every chunk of the region, coloured by
+    /// `coloring`, or `None` if any is missing, stale or unreadable; those
+    /// are logged, and regenerating the region replaces them.
+    pub fn load_region(
+        &self,
+        settings: &TerrainSettings,
+        coloring: &TerrainColoring,
+        min_chunk: IVec2,
+        max_chunk: IVec2,
+    ) -> Option<HashMap<IVec2, Arc<ChunkSamples>>> {
+        let mut cached = HashMap::new();
+        for chunk in chunks(min_chunk, max_chunk) {
+            match self.load(chunk) {
+                Ok(Some(mut samples)) => {
+                    samples.color(settings, coloring, chunk);
+                    cached.insert(chunk, Arc::new(samples));
+                }
+                Ok(None) => return None,
+                Err(ChunkCacheError::Stale) => {
+                    debug!("cached chunk {chunk} is stale, regenerating");
+                    return None;
+                }
+                Err(err) => {
+                    warn!("ignoring cached chunk {chunk} in {:?}: {err}", self.dir);
+                    return None;
+                }
+            }
+        }
+        Some(cached)
+    }
+
+    /// Samples every chunk of a freshly generated region and caches them,
+    /// logging any that can't be written.
+    pub fn store_region(
+        &self,
+        settings: &TerrainSettings,
+        region: &TerrainRegion,
+        coloring: &TerrainColoring,
+    ) -> HashMap<IVec2, Arc<ChunkSamples>> {
+        chunks(region.min_chunk, region.max_chunk)
+            .map(|chunk| {
+                let samples = ChunkSamples::new(settings, region, coloring, chunk);
+                if let Err(err) = self.store(chunk, &samples) {
+                    warn!("failed to cache chunk {chunk} in {:?}: {err}", self.dir);
+                }
+                (chunk, Arc::new(samples))
+            })
+            .collect()
+    }
+}
+
+fn chunks(min_chunk: IVec2, max_chunk: IVec2) -> impl Iterator<Item = IVec2> {
+    (min_chunk.x..=max_chunk.x)
+        .flat_map(move |x| (min_chunk.y..=max_chunk.y).map(move |z| IVec2::new(x, z)))
+}
+
+/// The region the cached chunks were generated as, with one sample of
+/// apron instead of the erosion overlap. Neighbouring chunks overlap by
+/// their edge and apron samples, which hold the same heights.
+pub fn region_from_samples(
+    min_chunk: IVec2,
+    max_chunk: IVec2,
+    cached: &HashMap<IVec2, Arc<ChunkSamples>>,
+) -> TerrainRegion {
+    let mut region = TerrainRegion::new(min_chunk, max_chunk, 1);
+    for (chunk, samples) in cached {
+        let (first_x, first_z) = region.chunk_sample(*chunk, 0, 0);
+        for (i, height) in samples.heights.iter().enumerate() {
+            let (x, z) = (i % ChunkSamples::SIZE, i / ChunkSamples::SIZE);
+            region
+                .heights
+                .set(first_x - 1 + x, first_z - 1 + z, *height);
+        }
+    }
+    region
+}
+
+/// Hash of everything that changes a chunk's heights or normals. Settings
+/// that only change how chunks are meshed, coloured or drawn are reset
+/// first, so tweaking them keeps the cache; anything added later counts
+/// until it's reset here too.
+fn settings_key(settings: &TerrainSettings, min_chunk: IVec2, max_chunk: IVec2) -> u64 {
+    let defaults = TerrainSettings::default();
+    let generation = TerrainSettings {
+        normals: defaults.normals,
+        shading: defaults.shading,
+        displacement: defaults.displacement,
+        palette: defaults.palette,
+        streaming: defaults.streaming,
+        lod: defaults.lod,
+        chunk_tasks: defaults.chunk_tasks,
+        mode: defaults.mode,
+        quadtree: defaults.quadtree,
+        clipmap: defaults.clipmap,
+        cache: defaults.cache,
+        ..settings.clone()
+    };
+    // imported heights change without the settings changing
+    let file = match &settings.source {
+        HeightSource::Procedural => None,
+        HeightSource::Heightmap(import) => Some(&import.path),
+        HeightSource::Dem(import) => Some(&import.path),
+    }
+    .and_then(|path| std::fs::metadata(path).ok())
+    .map(|metadata| (metadata.len(), metadata.modified().ok()));
+
+    let input = format!("{generation:?} {file:?} {min_chunk} {max_chunk}");
+    fnv1a(input.as_bytes())
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// CRC-32 (IEEE), bit by bit; entries are only a few kilobytes.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// Little-endian header, samples, then the CRC-32 of everything before it.
+fn encode(samples: &ChunkSamples, key: u64, chunk: IVec2) -> Vec<u8> {
+    let size = ChunkSamples::SIZE;
+    let mut bytes = Vec::with_capacity(HEADER_BYTES + size * size * SAMPLE_BYTES + 4);
+    bytes.extend(MAGIC);
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(key.to_le_bytes());
+    bytes.extend(chunk.x.to_le_bytes());
+    bytes.extend(chunk.y.to_le_bytes());
+    bytes.extend((size as u32).to_le_bytes());
+    for (height, normal) in samples.heights.iter().zip(&samples.normals) {
+        bytes.extend(height.to_le_bytes());
+        // terrain normals always point up, so y follows from x and z
+        for axis in [normal.x, normal.z] {
+            bytes.extend(((axis.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16).to_le_bytes());
+        }
+    }
+    let crc = crc32(&bytes);
+    bytes.extend(crc.to_le_bytes());
+    bytes
+}
+
+fn decode(bytes: &[u8], key: u64, chunk: IVec2) -> Result<ChunkSamples, ChunkCacheError> {
+    let size = ChunkSamples::SIZE;
+    let expected = HEADER_BYTES + size * size * SAMPLE_BYTES + 4;
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(ChunkCacheError::BadMagic);
+    }
+    // checked before the length, as other versions lay out differently
+    if let Some(version) = bytes.get(MAGIC.len()..MAGIC.len() + 4) {
+        let version = u32::from_le_bytes(version.try_into().unwrap());
+        if version != VERSION {
+            return Err(ChunkCacheError::Version(version));
+        }
+    }
+    if bytes.len() != expected {
+        return Err(ChunkCacheError::Truncated {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+    let (body, crc) = bytes.split_at(expected - 4);
+    if crc32(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(ChunkCacheError::Checksum);
+    }
+
+    let mut cursor = &body[MAGIC.len() + 4..];
+    let mut take = |n: usize| {
+        let (taken, rest) = cursor.split_at(n);
+        cursor = rest;
+        taken
+    };
+    let stored_key = u64::from_le_bytes(take(8).try_into().unwrap());
+    let x = i32::from_le_bytes(take(4).try_into().unwrap());
+    let z = i32::from_le_bytes(take(4).try_into().unwrap());
+    let stored_size = u32::from_le_bytes(take(4).try_into().unwrap());
+    if stored_key != key || IVec2::new(x, z) != chunk || stored_size as usize != size {
+        return Err(ChunkCacheError::Stale);
+    }
+
+    let count = size * size;
+    let mut samples = ChunkSamples {
+        heights: Vec::with_capacity(count),
+        normals: Vec::with_capacity(count),
+        colors: Vec::new(),
+    };
+    for _ in 0..count {
+        samples
+            .heights
+            .push(f32::from_le_bytes(take(4).try_into().unwrap()));
+        let mut axis = || i16::from_le_bytes(take(2).try_into().unwrap()) as f32 / i16::MAX as f32;
+        let (nx, nz) = (axis(), axis());
+        let ny = (1.0 - nx * nx - nz * nz).max(0.0).sqrt();
+        samples.normals.push(Vec3::new(nx, ny, nz).normalize());
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: u64 = 0x1234_5678_9abc_def0;
+    const CHUNK: IVec2 = IVec2::new(-3, 2);
+
+    fn samples() -> ChunkSamples {
+        let count = ChunkSamples::SIZE * ChunkSamples::SIZE;
+        ChunkSamples {
+            heights: (0..count).map(|i| (i as f32 * 0.37).sin() * 80.0).collect(),
+            normals: (0..count)
+                .map(|i| Vec3::new((i as f32).cos() * 0.3, 1.0, (i as f32).sin() * 0.2).normalize())
+                .collect(),
+            colors: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn round_trip_keeps_heights_and_normals() {
+        let samples = samples();
+        let decoded = decode(&encode(&samples, KEY, CHUNK), KEY, CHUNK).unwrap();
+        assert_eq!(decoded.heights, samples.heights);
+        for (decoded, normal) in decoded.normals.iter().zip(&samples.normals) {
+            assert!(decoded.distance(*normal) < 1e-3, "{decoded} vs {normal}");
+        }
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let bytes = encode(&samples(), KEY, CHUNK);
+        let result = decode(&bytes[..bytes.len() - 10], KEY, CHUNK);
+        assert!(matches!(result, Err(ChunkCacheError::Truncated { .. })));
+    }
+
+    #[test]
+    fn flipped_byte_fails_the_checksum() {
+        let mut bytes = encode(&samples(), KEY, CHUNK);
+        bytes[HEADER_BYTES + 100] ^= 0x10;
+        assert!(matches!(
+            decode(&bytes, KEY, CHUNK),
+            Err(ChunkCacheError::Checksum)
+        ));
+    }
+
+    #[test]
+    fn other_version_is_rejected() {
+        let mut bytes = encode(&samples(), KEY, CHUNK);
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            decode(&bytes, KEY, CHUNK),
+            Err(ChunkCacheError::Version(version)) if version == VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn other_settings_or_chunk_are_stale() {
+        let bytes = encode(&samples(), KEY, CHUNK);
+        assert!(matches!(
+            decode(&bytes, KEY + 1, CHUNK),
+            Err(ChunkCacheError::Stale)
+        ));
+        assert!(matches!(
+            decode(&bytes, KEY, CHUNK + IVec2::X),
+            Err(ChunkCacheError::Stale)
+        ));
+    }
+
+    #[test]
+    fn other_files_are_not_cache_entries() {
+        assert!(matches!(
+            decode(b"\x89PNG\r\n", KEY, CHUNK),
+            Err(ChunkCacheError::BadMagic)
+        ));
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy::tasks::futures_lite::future;
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+use bevy::utils::{Duration, HashMap, Instant};
+
+use crate::biome::TerrainColoring;
+use crate::chunk_cache::ChunkSamples;
+use crate::heightfield::{TerrainRegion, TerrainSurface};
+use crate::heightmap::Heightmap;
+use crate::lod::ChunkDetail;
+use crate::seams::SeamCost;
+use crate::terrain_material::DisplacementMaps;
+use crate::terrain_settings::TerrainSettings;
+use crate::{cached_region, generate_chunk, generate_grid};
+
+/// Time spent in the generation stages that have their own diagnostics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StageTimes {
+    /// `None` when the pass is switched off.
+    pub erosion: Option<Duration>,
+    pub weathering: Option<Duration>,
+    pub normals: Duration,
+}
+
+/// Heights a chunk is meshed from.
+#[derive(Clone)]
+pub enum ChunkSource {
+    /// An already generated region covering the chunk (a whole grid's, or
+    /// the chunk's own), the heightmap it was sampled from, if any, and the
+    /// chunk's samples when the chunk cache is on.
+    Region {
+        region: Arc<TerrainRegion>,
+        heightmap: Option<Arc<Heightmap>>,
+        samples: Option<Arc<ChunkSamples>>,
+    },
+    /// The noise stack, sampled into a one-chunk region of the chunk's own.
+    Noise,
+}
+
+impl ChunkSource {
+    /// The same heights without the cached samples, whose colours are out
+    /// of date once the palette changes.
+    pub fn recolored(&self) -> Self {
+        match self {
+            ChunkSource::Region {
+                region, heightmap, ..
+            } => ChunkSource::Region {
+                region: region.clone(),
+                heightmap: heightmap.clone(),
+                samples: None,
+            },
+            ChunkSource::Noise => ChunkSource::Noise,
+        }
+    }
+}
+
+/// A grid's shared region, ready for its chunks to be queued.
+pub struct GeneratedGrid {
+    pub region: Arc<TerrainRegion>,
+    pub heightmap: Option<Arc<Heightmap>>,
+    /// Each chunk's samples, when the chunk cache is on.
+    pub samples: HashMap<IVec2, Arc<ChunkSamples>>,
+    pub times: StageTimes,
+}
+
+/// A generated chunk's geometry, by `Displacement`.
+pub enum ChunkMesh {
+    Cpu(Mesh),
+    Gpu(Box<DisplacementMaps>),
+}
+
+pub struct GeneratedChunk {
+    pub chunk: IVec2,
+    pub detail: ChunkDetail,
+    pub mesh: ChunkMesh,
+    pub seam: SeamCost,
+    /// The chunk's heights, kept for meshing it again at another level.
+    pub source: ChunkSource,
+    pub times: StageTimes,
+    /// Time spent generating on the task's thread.
+    pub generation: Duration,
+    /// Time from being queued to finishing, including waiting for a slot.
+    pub latency: Duration,
+}
+
+struct QueuedChunk {
+    chunk: IVec2,
+    source: ChunkSource,
+    queued: Instant,
+}
+
+struct RunningChunk {
+    chunk: IVec2,
+    queued: Instant,
+    task: Task<GeneratedChunk>,
+}
+
+/// Progress of a fixed grid of chunks, for the whole-terrain totals.
+pub struct GridBuild {
+    pub started: Instant,
+    pub chunks: usize,
+    pub remaining: usize,
+    pub vertices: usize,
+    pub normals: Duration,
+}
+
+/// Terrain generation running on the `AsyncComputeTaskPool`. Chunks wait in
+/// a queue until a task slot frees up; dropping a task cancels it, so
+/// `clear` stops everything in flight.
+#[derive(Resource, Default)]
+pub struct ChunkTasks {
+    grid: Option<Task<GeneratedGrid>>,
+    /// The settings' heightmap, loading for terrain sampled straight from
+    /// its surface.
+    heightmap: Option<Task<Option<Arc<Heightmap>>>>,
+    queue: VecDeque<QueuedChunk>,
+    running: Vec<RunningChunk>,
+    /// Set while a fixed grid is generating.
+    pub build: Option<GridBuild>,
+}
+
+impl ChunkTasks {
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Generates the settings' chunk grid: its shared region first, then
+    /// each chunk once that's done.
+    pub fn start_grid(&mut self, settings: &TerrainSettings, coloring: Arc<TerrainColoring>) {
+        let settings = settings.clone();
+        self.grid = Some(
+            AsyncComputeTaskPool::get().spawn(async move { generate_grid(&settings, &coloring) }),
+        );
+        self.build = Some(GridBuild {
+            started: Instant::now(),
+            chunks: 0,
+            remaining: 0,
+            vertices: 0,
+            normals: Duration::ZERO,
+        });
+    }
+
+    /// Loads the settings' heightmap, if they have one, off the main thread.
+    pub fn start_heightmap(&mut self, settings: &TerrainSettings) {
+        let settings = settings.clone();
+        self.heightmap = Some(
+            AsyncComputeTaskPool::get()
+                .spawn(async move { settings.load_heightmap().map(Arc::new) }),
+        );
+    }
+
+    /// The loaded heightmap (`None` for procedural terrain, or if it failed
+    /// to load), once its task is done.
+    pub fn finished_heightmap(&mut self) -> Option<Option<Arc<Heightmap>>> {
+        let heightmap = block_on(future::poll_once(self.heightmap.as_mut()?))?;
+        self.heightmap = None;
+        Some(heightmap)
+    }
+
+    /// The grid's region, once its task is done.
+    pub fn finished_grid(&mut self) -> Option<GeneratedGrid> {
+        let grid = block_on(future::poll_once(self.grid.as_mut()?))?;
+        self.grid = None;
+        Some(grid)
+    }
+
+    /// Queues every chunk of a finished grid to be meshed from its region.
+    pub fn queue_grid(&mut self, grid: &GeneratedGrid) {
+        let (min_chunk, max_chunk) = (grid.region.min_chunk, grid.region.max_chunk);
+        let queued = Instant::now();
+        for x in min_chunk.x..=max_chunk.x {
+            for z in min_chunk.y..=max_chunk.y {
+                let chunk = IVec2::new(x, z);
+                self.queue.push_back(QueuedChunk {
+                    chunk,
+                    source: ChunkSource::Region {
+                        region: grid.region.clone(),
+                        heightmap: grid.heightmap.clone(),
+                        samples: grid.samples.get(&chunk).cloned(),
+                    },
+                    queued,
+                });
+            }
+        }
+        if let Some(build) = &mut self.build {
+            build.chunks = self.queue.len();
+            build.remaining = self.queue.len();
+        }
+    }
+
+    /// Queues `chunk` unless it's already queued or running.
+    pub fn queue(&mut self, chunk: IVec2, source: ChunkSource) {
+        if !self.contains(chunk) {
+            self.queue.push_back(QueuedChunk {
+                chunk,
+                source,
+                queued: Instant::now(),
+            });
+        }
+    }
+
+    /// Replaces the queued noise chunks with `chunks`, in order, after any
+    /// queued re-meshes. Chunks that were already waiting keep their place
+    /// in the latency measurements; running chunks are left alone.
+    pub fn queue_noise(&mut self, chunks: impl IntoIterator<Item = IVec2>) {
+        let now = Instant::now();
+        let noise: Vec<QueuedChunk> = chunks
+            .into_iter()
+            .filter(|chunk| !self.is_running(*chunk))
+            .map(|chunk| QueuedChunk {
+                chunk,
+                source: ChunkSource::Noise,
+                queued: self
+                    .queue
+                    .iter()
+                    .find(|queued| queued.chunk == chunk)
+                    .map_or(now, |queued| queued.queued),
+            })
+            .collect();
+        self.queue
+            .retain(|queued| matches!(queued.source, ChunkSource::Region { .. }));
+        self.queue.extend(noise);
+    }
+
+    /// Drops the queued and running chunks `keep` rejects, cancelling their
+    /// tasks, so a chunk unloaded while it generates isn't spawned again.
+    pub fn retain(&mut self, keep: impl Fn(IVec2) -> bool) {
+        self.queue.retain(|queued| keep(queued.chunk));
+        self.running.retain(|running| keep(running.chunk));
+    }
+
+    pub fn is_running(&self, chunk: IVec2) -> bool {
+        self.running.iter().any(|running| running.chunk == chunk)
+    }
+
+    pub fn contains(&self, chunk: IVec2) -> bool {
+        self.is_running(chunk) || self.queue.iter().any(|queued| queued.chunk == chunk)
+    }
+
+    /// Chunks waiting for a task slot.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Starts queued chunks, in order, until `limit` are running. Each is
+    /// meshed at the LOD levels `detail` picks for it as it starts.
+    pub fn start(
+        &mut self,
+        limit: usize,
+        settings: &TerrainSettings,
+        coloring: &Arc<TerrainColoring>,
+        detail: impl Fn(IVec2) -> ChunkDetail,
+    ) {
+        if self.running.len() >= limit || self.queue.is_empty() {
+            return;
+        }
+        // one copy shared by every task started this frame
+        let settings = Arc::new(settings.clone());
+        let pool = AsyncComputeTaskPool::get();
+
+        while self.running.len() < limit {
+            let Some(QueuedChunk {
+                chunk,
+                source,
+                queued,
+            }) = self.queue.pop_front()
+            else {
+                break;
+            };
+            let detail = detail(chunk);
+            let settings = settings.clone();
+            let coloring = coloring.clone();
+            let task = pool
+                .spawn(async move { generate_queued(&settings, &coloring, chunk, detail, source) });
+            self.running.push(RunningChunk {
+                chunk,
+                queued,
+                task,
+            });
+        }
+    }
+
+    /// Takes the chunks whose tasks have finished.
+    pub fn finished(&mut self) -> Vec<GeneratedChunk> {
+        let mut finished = Vec::new();
+        self.running.retain_mut(|running| {
+            let Some(generated) = block_on(future::poll_once(&mut running.task)) else {
+                return true;
+            };
+            finished.push(GeneratedChunk {
+                latency: running.queued.elapsed(),
+                ..generated
+            });
+            false
+        });
+        finished
+    }
+}
+
+/// Body of a chunk task; `latency` is filled in once it's collected.
+fn generate_queued(
+    settings: &TerrainSettings,
+    coloring: &TerrainColoring,
+    chunk: IVec2,
+    detail: ChunkDetail,
+    source: ChunkSource,
+) -> GeneratedChunk {
+    let started = Instant::now();
+    let mut times = StageTimes::default();
+    // the noise isn't `Send`, so each task builds its own surface
+    let (region, heightmap, samples, surface) = match source {
+        ChunkSource::Region {
+            region,
+            heightmap,
+            samples,
+        } => {
+            let surface = TerrainSurface::new(settings, heightmap.clone(), region.min_chunk);
+            (region, heightmap, samples, surface)
+        }
+        ChunkSource::Noise => {
+            let surface = TerrainSurface::new(settings, None, chunk);
+            let unerroded = settings.without_erosion();
+            let (region, mut samples) =
+                cached_region(&unerroded, coloring, chunk, chunk, &surface, &mut times);
+            (Arc::new(region), None, samples.remove(&chunk), surface)
+        }
+    };
+    let (mesh, seam) = if settings.displaces_on_gpu() {
+        let started = Instant::now();
+        let samples = samples
+            .clone()
+            .unwrap_or_else(|| Arc::new(ChunkSamples::new(settings, &region, coloring, chunk)));
+        let maps = DisplacementMaps::new(&samples, chunk);
+        times.normals += started.elapsed();
+        (ChunkMesh::Gpu(Box::new(maps)), SeamCost::default())
+    } else {
+        let (mesh, seam) = generate_chunk(
+            settings,
+            &region,
+            &surface,
+            coloring,
+            chunk,
+            detail,
+            &mut times.normals,
+        );
+        (ChunkMesh::Cpu(mesh), seam)
+    };
+
+    GeneratedChunk {
+        chunk,
+        detail,
+        mesh,
+        seam,
+        source: ChunkSource::Region {
+            region,
+            heightmap,
+            samples,
+        },
+        times,
+        generation: started.elapsed(),
+        latency: Duration::ZERO,
+    }
+}
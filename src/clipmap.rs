@@ -0,0 +1,335 @@
+use bevy::asset::RenderAssetUsages;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use serde::Deserialize;
+
+use crate::biome::TerrainColoring;
+use crate::color_chunk;
+use crate::normals::{self, ShadingMode};
+use crate::terrain_settings::TerrainSettings;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ClipmapSettings {
+    /// Nested rings around the camera, each twice as coarse and twice as
+    /// wide as the one inside it; at most `MAX_LEVELS`.
+    pub levels: usize,
+    /// Quads along each edge of every ring's window. Rounded up to a
+    /// multiple of four, so the ring inside sits on this ring's vertices.
+    pub grid: u32,
+    /// World units between the finest ring's vertices.
+    pub spacing: f32,
+}
+
+impl ClipmapSettings {
+    /// Most `levels` the ring spacings, which double per level, are
+    /// computed for.
+    pub const MAX_LEVELS: usize = 16;
+}
+
+impl Default for ClipmapSettings {
+    fn default() -> Self {
+        Self {
+            levels: 5,
+            grid: 64,
+            spacing: 16.0,
+        }
+    }
+}
+
+/// Heights of one ring's square window of samples. Samples are stored
+/// toroidally, at their grid coordinates modulo the window size, so moving
+/// the window only samples the rows and columns it moved onto; the rest
+/// stay where they are.
+pub struct ClipmapLevel {
+    /// Samples along each edge of the window.
+    size: usize,
+    /// World units between samples.
+    spacing: f64,
+    /// Grid coordinates of the window's first sample; `None` until the
+    /// first update.
+    origin: Option<IVec2>,
+    heights: Vec<f32>,
+}
+
+impl ClipmapLevel {
+    pub fn new(size: usize, spacing: f64) -> Self {
+        Self {
+            size,
+            spacing,
+            origin: None,
+            heights: vec![0.0; size * size],
+        }
+    }
+
+    fn slot(&self, sample: IVec2) -> usize {
+        let size = self.size as i32;
+        (sample.y.rem_euclid(size) * size + sample.x.rem_euclid(size)) as usize
+    }
+
+    fn contains(origin: IVec2, size: usize, sample: IVec2) -> bool {
+        let offset = sample - origin;
+        offset.cmpge(IVec2::ZERO).all() && offset.cmplt(IVec2::splat(size as i32)).all()
+    }
+
+    /// Moves the window to start at grid coordinates `origin`, sampling
+    /// `height(world_x, world_z)` for the samples that weren't already in
+    /// it. Returns how many were sampled.
+    pub fn update(&mut self, origin: IVec2, height: impl Fn(f64, f64) -> f32) -> usize {
+        let previous = self.origin.replace(origin);
+        if previous == Some(origin) {
+            return 0;
+        }
+        let size = self.size as i32;
+        let mut sampled = 0;
+        for z in origin.y..origin.y + size {
+            for x in origin.x..origin.x + size {
+                let sample = IVec2::new(x, z);
+                if previous.is_some_and(|previous| Self::contains(previous, self.size, sample)) {
+                    continue;
+                }
+                let slot = self.slot(sample);
+                self.heights[slot] = height(x as f64 * self.spacing, z as f64 * self.spacing);
+                sampled += 1;
+            }
+        }
+        sampled
+    }
+
+    /// Height at grid coordinates `sample`, which must be in the window.
+    pub fn height(&self, sample: IVec2) -> f32 {
+        debug_assert!(self
+            .origin
+            .is_some_and(|origin| Self::contains(origin, self.size, sample)));
+        self.heights[self.slot(sample)]
+    }
+}
+
+/// Nested rings of fixed-size grids centred on the camera. Each ring
+/// leaves a hole for the one inside it, which always starts a quarter or a
+/// quarter plus one quad into it.
+pub struct Clipmap {
+    /// Quads along each edge of a window.
+    grid: usize,
+    levels: Vec<ClipmapLevel>,
+}
+
+impl Clipmap {
+    pub fn new(settings: &ClipmapSettings) -> Self {
+        let grid = (settings.grid.max(4) as usize).next_multiple_of(4);
+        Self {
+            grid,
+            levels: (0..settings.levels.max(1))
+                .map(|level| {
+                    ClipmapLevel::new(grid + 1, settings.spacing as f64 * (1 << level) as f64)
+                })
+                .collect(),
+        }
+    }
+
+    /// Window origins for a camera at world `(x, z)`, finest first. Each
+    /// is snapped to an even sample, so it starts on a vertex of the next
+    /// coarser ring.
+    pub fn origins(&self, camera: Vec2) -> Vec<IVec2> {
+        self.levels
+            .iter()
+            .map(|level| {
+                let snapped = (camera.as_dvec2() / (level.spacing * 2.0))
+                    .floor()
+                    .as_ivec2()
+                    * 2;
+                snapped - IVec2::splat(self.grid as i32 / 2)
+            })
+            .collect()
+    }
+
+    /// Moves every window around a camera at world `(x, z)`. Returns the
+    /// samples taken per level; a level with none hasn't moved.
+    pub fn update(&mut self, camera: Vec2, height: impl Fn(f64, f64) -> f32) -> Vec<usize> {
+        let origins = self.origins(camera);
+        self.levels
+            .iter_mut()
+            .zip(origins)
+            .map(|(level, origin)| level.update(origin, &height))
+            .collect()
+    }
+
+    /// World (x, z) of the outermost ring's centre and its edge length.
+    pub fn extent(&self) -> Option<(Vec2, f32)> {
+        let outermost = self.levels.last()?;
+        let size = (self.grid as f64 * outermost.spacing) as f32;
+        let origin = (outermost.origin?.as_dvec2() * outermost.spacing).as_vec2();
+        Some((origin + Vec2::splat(size / 2.0), size))
+    }
+
+    /// First quad of the hole `level` leaves for the ring inside it, in
+    /// quads from its own origin.
+    pub fn hole(&self, level: usize) -> Option<IVec2> {
+        let inner = self.levels.get(level.checked_sub(1)?)?.origin?;
+        Some(inner / 2 - self.levels[level].origin?)
+    }
+
+    /// Mesh of `level`'s ring as it stands: flat-shaded or smooth by the
+    /// settings, with the outermost odd vertices of every ring but the last
+    /// pulled onto the line between their neighbours, where the coarser
+    /// ring's edge runs.
+    pub fn mesh(
+        &self,
+        level: usize,
+        settings: &TerrainSettings,
+        coloring: &TerrainColoring,
+    ) -> Mesh {
+        let ring = &self.levels[level];
+        let origin = ring.origin.unwrap_or_default();
+        let size = ring.size;
+        let last = size as i32 - 1;
+        let outermost = level + 1 == self.levels.len();
+
+        let height = |x: i32, z: i32| {
+            let sample = origin + IVec2::new(x.clamp(0, last), z.clamp(0, last));
+            let edge_x = x == 0 || x == last;
+            let edge_z = z == 0 || z == last;
+            // on a shared edge: halfway between the even neighbours
+            if !outermost && edge_z && x % 2 == 1 {
+                (ring.height(sample - IVec2::X) + ring.height(sample + IVec2::X)) / 2.0
+            } else if !outermost && edge_x && z % 2 == 1 {
+                (ring.height(sample - IVec2::Y) + ring.height(sample + IVec2::Y)) / 2.0
+            } else {
+                ring.height(sample)
+            }
+        };
+
+        let mut positions = Vec::with_capacity(size * size);
+        let mut vertex_normals = Vec::with_capacity(size * size);
+        for z in 0..=last {
+            for x in 0..=last {
+                let world = (origin + IVec2::new(x, z)).as_dvec2() * ring.spacing;
+                positions.push([world.x as f32, height(x, z), world.y as f32]);
+
+                // central differences, one-sided at the window's edge
+                let (left, right) = ((x - 1).max(0), (x + 1).min(last));
+                let (up, down) = ((z - 1).max(0), (z + 1).min(last));
+                let spacing = ring.spacing as f32;
+                let dx = (height(right, z) - height(left, z)) / ((right - left) as f32 * spacing);
+                let dz = (height(x, down) - height(x, up)) / ((down - up) as f32 * spacing);
+                vertex_normals.push(Vec3::new(-dx, 1.0, -dz).normalize().to_array());
+            }
+        }
+
+        let hole = self.hole(level).map(|hole| {
+            let quads = self.grid as i32 / 2;
+            (hole, hole + IVec2::splat(quads))
+        });
+        let mut indices = Vec::with_capacity(self.grid * self.grid * 6);
+        for z in 0..last {
+            for x in 0..last {
+                let quad = IVec2::new(x, z);
+                if hole.is_some_and(|(min, max)| quad.cmpge(min).all() && quad.cmplt(max).all()) {
+                    continue;
+                }
+                let a = (z * (last + 1) + x) as u32;
+                let (b, c, d) = (a + 1, a + size as u32, a + size as u32 + 1);
+                indices.extend([a, c, b, b, c, d]);
+            }
+        }
+
+        let mut mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::default(),
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, vertex_normals)
+        .with_inserted_indices(Indices::U32(indices));
+        if settings.shading == ShadingMode::Flat {
+            normals::flat_shade(&mut mesh);
+        }
+        color_chunk(&mut mesh, settings, coloring);
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn height(x: f64, z: f64) -> f32 {
+        (x * 0.37 + z * 1.91).sin() as f32 * 10.0 + (x - z) as f32 * 0.01
+    }
+
+    fn assert_window(level: &ClipmapLevel) {
+        let origin = level.origin.unwrap();
+        for z in 0..level.size as i32 {
+            for x in 0..level.size as i32 {
+                let sample = origin + IVec2::new(x, z);
+                let world = sample.as_dvec2() * level.spacing;
+                assert_eq!(level.height(sample), height(world.x, world.y));
+            }
+        }
+    }
+
+    #[test]
+    fn first_update_samples_the_whole_window() {
+        let mut level = ClipmapLevel::new(9, 2.0);
+        assert_eq!(level.update(IVec2::new(-4, -4), height), 81);
+        assert_window(&level);
+    }
+
+    #[test]
+    fn staying_put_samples_nothing() {
+        let mut level = ClipmapLevel::new(9, 2.0);
+        level.update(IVec2::new(3, 5), height);
+        assert_eq!(level.update(IVec2::new(3, 5), height), 0);
+    }
+
+    #[test]
+    fn moves_only_sample_new_rows_and_columns() {
+        let mut level = ClipmapLevel::new(9, 2.0);
+        level.update(IVec2::ZERO, height);
+        assert_eq!(level.update(IVec2::new(1, 0), height), 9);
+        assert_eq!(level.update(IVec2::new(1, -2), height), 18);
+        assert_eq!(level.update(IVec2::new(3, -1), height), 9 * 9 - 7 * 8);
+        assert_window(&level);
+    }
+
+    #[test]
+    fn wrapped_window_matches_a_fresh_one_after_many_moves() {
+        let mut level = ClipmapLevel::new(17, 0.5);
+        let mut origin = IVec2::new(-8, -8);
+        level.update(origin, height);
+        for step in [
+            (1, 0),
+            (0, 3),
+            (-5, -2),
+            (20, 1),
+            (2, 2),
+            (-1, 17),
+            (0, -40),
+        ] {
+            origin += IVec2::from(step);
+            level.update(origin, height);
+            assert_window(&level);
+        }
+    }
+
+    #[test]
+    fn each_ring_sits_in_the_next_ones_hole() {
+        let mut clipmap = Clipmap::new(&ClipmapSettings {
+            levels: 4,
+            grid: 16,
+            spacing: 1.0,
+        });
+        for camera in [Vec2::ZERO, Vec2::new(5.3, -2.2), Vec2::new(-31.9, 47.5)] {
+            clipmap.update(camera, height);
+            for level in 1..4 {
+                let hole = clipmap.hole(level).unwrap();
+                for axis in [hole.x, hole.y] {
+                    assert!(axis == 4 || axis == 5, "hole at {hole} in level {level}");
+                }
+            }
+            // the camera is in the finest window
+            let finest = &clipmap.levels[0];
+            let offset = camera.as_dvec2() / finest.spacing - finest.origin.unwrap().as_dvec2();
+            assert!(offset.min_element() >= 0.0 && offset.max_element() <= 16.0);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use std::fmt;
+use std::path::Path;
+
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::heightfield::Heightfield;
+use crate::heightmap::Heightmap;
+
+/// Metres per degree of latitude; longitude is scaled by `cos(latitude)`.
+const METRES_PER_DEGREE: f64 = 111_320.0;
+const HGT_NO_DATA: i16 = -32768;
+
+/// Real-world elevation data: SRTM `.hgt` tiles or ESRI ASCII grids
+/// (`.asc`), picked by file extension.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DemImport {
+    pub path: String,
+    /// Real-world metres per world unit, horizontally and vertically.
+    pub metres_per_unit: f32,
+    /// Extra vertical exaggeration on top of `metres_per_unit`.
+    pub vertical_scale: f32,
+    /// World height of sea level (elevation 0).
+    pub height_offset: f32,
+}
+
+impl Default for DemImport {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            // a 1 arc-second SRTM tile (~110km) spans ~200 chunks at 1:1,
+            // so default to squeezing it into a benchmark-sized grid
+            metres_per_unit: 20.0,
+            vertical_scale: 1.0,
+            height_offset: 0.0,
+        }
+    }
+}
+
+/// Geographic bounds of a DEM. Geographic grids are in degrees
+/// (`x` = longitude, `y` = latitude), projected ones in metres.
+#[derive(Clone, Copy, Debug)]
+pub struct GeoExtent {
+    pub min: DVec2,
+    pub max: DVec2,
+    pub geographic: bool,
+}
+
+impl GeoExtent {
+    /// Size of the extent in metres.
+    pub fn size_metres(&self) -> DVec2 {
+        let size = self.max - self.min;
+        if self.geographic {
+            let latitude = ((self.min.y + self.max.y) / 2.0).to_radians();
+            DVec2::new(
+                size.x * METRES_PER_DEGREE * latitude.cos(),
+                size.y * METRES_PER_DEGREE,
+            )
+        } else {
+            size
+        }
+    }
+
+    /// Offset from the north-west corner, in metres, of a geographic or
+    /// projected coordinate. North is -z, matching the row order of the data.
+    pub fn offset_metres(&self, coord: DVec2) -> DVec2 {
+        let size = self.max - self.min;
+        let t = DVec2::new(
+            (coord.x - self.min.x) / size.x,
+            (self.max.y - coord.y) / size.y,
+        );
+        t * self.size_metres()
+    }
+}
+
+#[derive(Debug)]
+pub enum DemError {
+    Io(std::io::Error),
+    UnknownFormat(String),
+    HgtSize(usize),
+    AscHeader(String),
+    AscData { expected: usize, actual: usize },
+    NoData,
+}
+
+impl fmt::Display for DemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DemError::Io(err) => write!(f, "{err}"),
+            DemError::UnknownFormat(path) => {
+                write!(f, "{path:?} is neither an .hgt nor an .asc file")
+            }
+            DemError::HgtSize(len) => write!(f, "{len} bytes is not a square .hgt tile"),
+            DemError::AscHeader(msg) => write!(f, "bad .asc header: {msg}"),
+            DemError::AscData { expected, actual } => {
+                write!(f, ".asc grid has {actual} values, expected {expected}")
+            }
+            DemError::NoData => write!(f, "DEM contains only no-data values"),
+        }
+    }
+}
+
+impl std::error::Error for DemError {}
+
+impl From<std::io::Error> for DemError {
+    fn from(err: std::io::Error) -> Self {
+        DemError::Io(err)
+    }
+}
+
+/// Elevations in metres, `None` where the source has no data.
+struct Grid {
+    width: usize,
+    depth: usize,
+    values: Vec<Option<f32>>,
+    extent: GeoExtent,
+}
+
+pub struct Dem {
+    pub heightmap: Heightmap,
+    pub extent: GeoExtent,
+}
+
+impl Dem {
+    pub fn load(import: &DemImport) -> Result<Self, DemError> {
+        let path = Path::new(&import.path);
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        let grid = match extension.as_deref() {
+            Some("hgt") => read_hgt(path)?,
+            Some("asc") => parse_asc(&std::fs::read_to_string(path)?)?,
+            _ => return Err(DemError::UnknownFormat(import.path.clone())),
+        };
+
+        let filled = fill_no_data(&grid)?;
+        let mut heights = Heightfield::new(grid.width, grid.depth);
+        for (h, metres) in heights.heights.iter_mut().zip(filled) {
+            *h = metres / import.metres_per_unit * import.vertical_scale + import.height_offset;
+        }
+
+        let size = grid.extent.size_metres() / import.metres_per_unit as f64;
+        Ok(Self {
+            heightmap: Heightmap {
+                heights,
+                extent: size.as_vec2(),
+            },
+            extent: grid.extent,
+        })
+    }
+}
+
+/// SRTM tiles are square grids of big-endian i16 metres, named after their
+/// south-west corner (`N45E006.hgt`), one degree across.
+fn read_hgt(path: &Path) -> Result<Grid, DemError> {
+    let bytes = std::fs::read(path)?;
+    let samples = bytes.len() / 2;
+    let side = (samples as f64).sqrt() as usize;
+    if side < 2 || side * side * 2 != bytes.len() {
+        return Err(DemError::HgtSize(bytes.len()));
+    }
+
+    let values = bytes
+        .chunks_exact(2)
+        .map(|pair| match i16::from_be_bytes([pair[0], pair[1]]) {
+            HGT_NO_DATA => None,
+            metres => Some(metres as f32),
+        })
+        .collect();
+
+    let name = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let south_west = parse_hgt_name(name).unwrap_or_else(|| {
+        warn!("can't read tile position from {name:?}, assuming N00E000");
+        DVec2::ZERO
+    });
+
+    Ok(Grid {
+        width: side,
+        depth: side,
+        values,
+        extent: GeoExtent {
+            min: south_west,
+            max: south_west + DVec2::ONE,
+            geographic: true,
+        },
+    })
+}
+
+/// `N45E006` -> (lon 6, lat 45), `S12W077` -> (lon -77, lat -12).
+fn parse_hgt_name(name: &str) -> Option<DVec2> {
+    let name = name.to_ascii_uppercase();
+    let lon_at = name.find(['E', 'W'])?;
+    let (lat, lon) = name.split_at(lon_at);
+
+    let sign = |hemisphere: char, negative: char| if hemisphere == negative { -1.0 } else { 1.0 };
+    let lat_sign = sign(lat.chars().next()?, 'S');
+    let lon_sign = sign(lon.chars().next()?, 'W');
+    let lat: f64 = lat.get(1..)?.parse().ok()?;
+    let lon: f64 = lon.get(1..)?.parse().ok()?;
+    Some(DVec2::new(lon * lon_sign, lat * lat_sign))
+}
+
+/// ESRI ASCII grid: a `key value` header followed by `nrows` rows of
+/// `ncols` values, north row first.
+fn parse_asc(text: &str) -> Result<Grid, DemError> {
+    let mut tokens = text.split_whitespace().peekable();
+
+    let mut header = std::collections::HashMap::new();
+    while let Some(token) = tokens.peek() {
+        if token.parse::<f64>().is_ok() {
+            break;
+        }
+        let key = tokens.next().unwrap_or_default().to_ascii_lowercase();
+        let value = tokens
+            .next()
+            .and_then(|value| value.parse::<f64>().ok())
+            .ok_or_else(|| DemError::AscHeader(format!("missing value for {key}")))?;
+        header.insert(key, value);
+    }
+
+    let get = |key: &str| {
+        header
+            .get(key)
+            .copied()
+            .ok_or_else(|| DemError::AscHeader(format!("missing {key}")))
+    };
+    let width = get("ncols")? as usize;
+    let depth = get("nrows")? as usize;
+    let cell = match (header.get("dx"), header.get("dy")) {
+        (Some(dx), Some(dy)) => DVec2::new(*dx, *dy),
+        _ => DVec2::splat(get("cellsize")?),
+    };
+    if width < 2 || depth < 2 {
+        return Err(DemError::AscHeader(format!(
+            "{width}x{depth} grid is too small"
+        )));
+    }
+
+    // corners refer to the outer edge of the cells, centres are half a cell in
+    let min = match (header.get("xllcorner"), header.get("yllcorner")) {
+        (Some(x), Some(y)) => DVec2::new(*x, *y) + cell / 2.0,
+        _ => DVec2::new(get("xllcenter")?, get("yllcenter")?),
+    };
+    let max = min + cell * DVec2::new((width - 1) as f64, (depth - 1) as f64);
+    let no_data = header.get("nodata_value").copied();
+
+    let values: Vec<Option<f32>> = tokens
+        .map(|token| {
+            token
+                .parse::<f64>()
+                .ok()
+                .filter(|value| Some(*value) != no_data)
+                .map(|value| value as f32)
+        })
+        .collect();
+    if values.len() != width * depth {
+        return Err(DemError::AscData {
+            expected: width * depth,
+            actual: values.len(),
+        });
+    }
+
+    // degrees if the whole grid fits on the globe with sub-degree cells
+    let geographic = cell.max_element() < 1.0
+        && min.x >= -180.0
+        && max.x <= 180.0
+        && min.y >= -90.0
+        && max.y <= 90.0;
+
+    Ok(Grid {
+        width,
+        depth,
+        values,
+        extent: GeoExtent {
+            min,
+            max,
+            geographic,
+        },
+    })
+}
+
+/// Fills no-data holes by repeatedly averaging the valid neighbours of the
+/// hole's border, growing inwards until every sample has a value.
+fn fill_no_data(grid: &Grid) -> Result<Vec<f32>, DemError> {
+    let mut values = grid.values.clone();
+    let mut missing = values.iter().filter(|value| value.is_none()).count();
+    if missing == values.len() {
+        return Err(DemError::NoData);
+    }
+    if missing > 0 {
+        info!("filling {missing} DEM no-data samples");
+    }
+
+    // each pass reads the last one's values and writes the next's
+    let mut next = values.clone();
+    while missing > 0 {
+        for z in 0..grid.depth {
+            for x in 0..grid.width {
+                let i = z * grid.width + x;
+                next[i] = values[i];
+                if values[i].is_some() {
+                    continue;
+                }
+
+                let mut sum = 0.0;
+                let mut count = 0;
+                for (dx, dz) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                    let (nx, nz) = (x as i64 + dx, z as i64 + dz);
+                    if nx < 0 || nz < 0 || nx >= grid.width as i64 || nz >= grid.depth as i64 {
+                        continue;
+                    }
+                    if let Some(value) = values[nz as usize * grid.width + nx as usize] {
+                        sum += value;
+                        count += 1;
+                    }
+                }
+                if count > 0 {
+                    next[i] = Some(sum / count as f32);
+                    missing -= 1;
+                }
+            }
+        }
+        std::mem::swap(&mut values, &mut next);
+    }
+
+    Ok(values.into_iter().map(Option::unwrap_or_default).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hgt_names_give_the_south_west_corner() {
+        assert_eq!(parse_hgt_name("N45E006"), Some(DVec2::new(6.0, 45.0)));
+        assert_eq!(parse_hgt_name("S12W077"), Some(DVec2::new(-77.0, -12.0)));
+        assert_eq!(parse_hgt_name("n00w001"), Some(DVec2::new(-1.0, 0.0)));
+        assert_eq!(parse_hgt_name("S01E179"), Some(DVec2::new(179.0, -1.0)));
+        assert_eq!(parse_hgt_name("tile"), None);
+        assert_eq!(parse_hgt_name("NxxE006"), None);
+    }
+
+    #[test]
+    fn asc_grid_reads_rows_and_no_data() {
+        let grid = parse_asc(
+            "ncols 3\nnrows 2\nxllcorner 0.0\nyllcorner 0.0\ncellsize 10.0\n\
+             NODATA_value -9999\n1 2 3\n4 -9999 6\n",
+        )
+        .unwrap();
+        assert_eq!((grid.width, grid.depth), (3, 2));
+        assert_eq!(
+            grid.values,
+            [Some(1.0), Some(2.0), Some(3.0), Some(4.0), None, Some(6.0)]
+        );
+        // corners are cell edges, the samples sit at the centres
+        assert_eq!(grid.extent.min, DVec2::new(5.0, 5.0));
+        assert_eq!(grid.extent.max, DVec2::new(25.0, 15.0));
+        assert!(!grid.extent.geographic);
+    }
+
+    #[test]
+    fn malformed_asc_headers_are_rejected() {
+        let no_rows = "ncols 2\nxllcenter 0\nyllcenter 0\ncellsize 1\n1 2\n3 4\n";
+        assert!(matches!(parse_asc(no_rows), Err(DemError::AscHeader(_))));
+        let no_value = "ncols\nnrows 2\n";
+        assert!(matches!(parse_asc(no_value), Err(DemError::AscHeader(_))));
+        let too_small = "ncols 1\nnrows 2\nxllcenter 0\nyllcenter 0\ncellsize 1\n1\n2\n";
+        assert!(matches!(parse_asc(too_small), Err(DemError::AscHeader(_))));
+        let short = "ncols 2\nnrows 2\nxllcenter 0\nyllcenter 0\ncellsize 1\n1 2 3\n";
+        assert!(matches!(
+            parse_asc(short),
+            Err(DemError::AscData {
+                expected: 4,
+                actual: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn no_data_is_filled_from_the_neighbours() {
+        let grid = parse_asc(
+            "ncols 3\nnrows 3\nxllcenter 0\nyllcenter 0\ncellsize 1\nNODATA_value 0\n\
+             0 0 0\n0 0 0\n0 0 8\n",
+        )
+        .unwrap();
+        let filled = fill_no_data(&grid).unwrap();
+        assert_eq!(filled, [8.0; 9]);
+
+        let empty = parse_asc(
+            "ncols 2\nnrows 2\nxllcenter 0\nyllcenter 0\ncellsize 1\nNODATA_value 0\n\
+             0 0\n0 0\n",
+        )
+        .unwrap();
+        assert!(matches!(fill_no_data(&empty), Err(DemError::NoData)));
+    }
+}
@@ -0,0 +1,364 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::heightfield::{Heightfield, TerrainRegion, CHUNK_QUADS};
+
+/// Particle-based hydraulic erosion: droplets run downhill, picking up
+/// sediment on steep ground and dropping it where they slow down.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct HydraulicErosion {
+    /// Number of droplets simulated per chunk; `0` disables the pass.
+    pub iterations: u32,
+    pub erosion_rate: f32,
+    pub deposition_rate: f32,
+    /// Fraction of a droplet's water lost per step.
+    pub evaporation: f32,
+    /// How much of a droplet's previous direction carries over (0..1).
+    pub inertia: f32,
+    pub sediment_capacity: f32,
+    pub min_slope: f32,
+    /// Radius in samples over which a droplet wears the ground down.
+    pub radius: f32,
+    pub gravity: f32,
+    pub max_lifetime: u32,
+    /// Samples simulated past the outermost chunk edges so droplets can
+    /// flow in and out of the region naturally.
+    pub overlap: usize,
+    /// Samples inside the region edge over which erosion fades out, so the
+    /// region border keeps the raw noise heights and lines up with whatever
+    /// is generated next to it.
+    pub fade: usize,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 0,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation: 0.01,
+            inertia: 0.05,
+            sediment_capacity: 1.0,
+            min_slope: 0.01,
+            radius: 3.0,
+            gravity: 4.0,
+            max_lifetime: 30,
+            overlap: 8,
+            fade: 4,
+        }
+    }
+}
+
+/// Small deterministic generator so erosion output only depends on the seed.
+pub struct SplitMix64(u64);
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0.0..1.0`.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Height and gradient at a fractional sample position, bilinearly
+/// interpolated from the four surrounding samples.
+fn height_and_gradient(heights: &Heightfield, pos: Vec2) -> (f32, Vec2) {
+    let x = pos.x as usize;
+    let z = pos.y as usize;
+    let u = pos.x - x as f32;
+    let v = pos.y - z as f32;
+
+    let h00 = heights.get(x, z);
+    let h10 = heights.get(x + 1, z);
+    let h01 = heights.get(x, z + 1);
+    let h11 = heights.get(x + 1, z + 1);
+
+    let gradient = Vec2::new(
+        (h10 - h00) * (1.0 - v) + (h11 - h01) * v,
+        (h01 - h00) * (1.0 - u) + (h11 - h10) * u,
+    );
+    let height =
+        h00 * (1.0 - u) * (1.0 - v) + h10 * u * (1.0 - v) + h01 * (1.0 - u) * v + h11 * u * v;
+    (height, gradient)
+}
+
+/// Adds `amount` to the four samples around `pos`, weighted bilinearly.
+fn deposit(heights: &mut Heightfield, pos: Vec2, amount: f32) {
+    let x = pos.x as usize;
+    let z = pos.y as usize;
+    let u = pos.x - x as f32;
+    let v = pos.y - z as f32;
+
+    for (dx, dz, weight) in [
+        (0, 0, (1.0 - u) * (1.0 - v)),
+        (1, 0, u * (1.0 - v)),
+        (0, 1, (1.0 - u) * v),
+        (1, 1, u * v),
+    ] {
+        let i = heights.index(x + dx, z + dz);
+        heights.heights[i] += amount * weight;
+    }
+}
+
+/// Removes `amount` from the samples within `radius` of `pos`, weighted by
+/// distance so droplets carve smooth channels instead of single-sample pits.
+fn wear(heights: &mut Heightfield, pos: Vec2, radius: f32, amount: f32) {
+    let min = (pos - radius).max(Vec2::ZERO).as_uvec2();
+    let max = (pos + radius)
+        .min(Vec2::new(
+            heights.width as f32 - 1.0,
+            heights.depth as f32 - 1.0,
+        ))
+        .as_uvec2();
+
+    let mut brush = Vec::new();
+    let mut total = 0.0;
+    for z in min.y..=max.y {
+        for x in min.x..=max.x {
+            let weight = radius - pos.distance(Vec2::new(x as f32, z as f32));
+            if weight > 0.0 {
+                brush.push((x as usize, z as usize, weight));
+                total += weight;
+            }
+        }
+    }
+
+    for (x, z, weight) in brush {
+        let i = heights.index(x, z);
+        heights.heights[i] -= amount * weight / total;
+    }
+}
+
+pub fn erode(heights: &mut Heightfield, params: &HydraulicErosion, droplets: u32, seed: u64) {
+    let mut rng = SplitMix64::new(seed);
+    let max = Vec2::new(heights.width as f32 - 1.0, heights.depth as f32 - 1.0);
+
+    for _ in 0..droplets {
+        let mut pos = Vec2::new(rng.next_f32() * max.x, rng.next_f32() * max.y);
+        let mut dir = Vec2::ZERO;
+        let mut speed = 1.0;
+        let mut water = 1.0;
+        let mut sediment = 0.0;
+
+        for _ in 0..params.max_lifetime {
+            let (height, gradient) = height_and_gradient(heights, pos);
+
+            dir = dir * params.inertia - gradient * (1.0 - params.inertia);
+            if dir.length_squared() < 1e-12 {
+                break;
+            }
+            dir = dir.normalize();
+
+            let next = pos + dir;
+            if next.x < 0.0 || next.y < 0.0 || next.x >= max.x || next.y >= max.y {
+                break;
+            }
+
+            let delta = height_and_gradient(heights, next).0 - height;
+            let capacity =
+                (-delta).max(params.min_slope) * speed * water * params.sediment_capacity;
+
+            if delta > 0.0 || sediment > capacity {
+                // uphill: fill the pit behind us; otherwise drop the excess
+                let amount = if delta > 0.0 {
+                    delta.min(sediment)
+                } else {
+                    (sediment - capacity) * params.deposition_rate
+                };
+                sediment -= amount;
+                deposit(heights, pos, amount);
+            } else {
+                // never dig deeper than the step we are about to take
+                let amount = ((capacity - sediment) * params.erosion_rate).min(-delta);
+                sediment += amount;
+                if params.radius > 1.0 {
+                    wear(heights, pos, params.radius, amount);
+                } else {
+                    deposit(heights, pos, -amount);
+                }
+            }
+
+            speed = (speed * speed - delta * params.gravity).max(0.0).sqrt();
+            water *= 1.0 - params.evaporation;
+            pos = next;
+        }
+    }
+}
+
+/// Replaces `region`'s heights with `modified`, faded back to `original`
+/// over `fade` samples inside the outer chunk edges. The apron and the
+/// region border keep the input heights so neighbouring regions line up.
+fn blend_into_region(
+    region: &mut TerrainRegion,
+    original: &Heightfield,
+    modified: &Heightfield,
+    fade: usize,
+) {
+    // distance in samples from the outer chunk edges, negative in the apron
+    let chunks = region.max_chunk - region.min_chunk + IVec2::ONE;
+    let inner = IVec2::new(
+        (chunks.x as usize * CHUNK_QUADS) as i32,
+        (chunks.y as usize * CHUNK_QUADS) as i32,
+    );
+    let apron = region.apron as i32;
+    for z in 0..original.depth {
+        for x in 0..original.width {
+            let local = IVec2::new(x as i32 - apron, z as i32 - apron);
+            let edge = local.min(inner - local).min_element();
+            let weight = if fade == 0 {
+                if edge > 0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            } else {
+                let t = (edge as f32 / fade as f32).clamp(0.0, 1.0);
+                t * t * (3.0 - 2.0 * t)
+            };
+
+            let i = original.index(x, z);
+            let raw = original.heights[i];
+            region.heights.heights[i] = raw + (modified.heights[i] - raw) * weight;
+        }
+    }
+}
+
+/// Erodes a whole region in place. Droplets are simulated on heights
+/// measured in sample spacings so the parameters don't depend on chunk size,
+/// and the result is faded back to the input towards the chunk border.
+pub fn erode_region(region: &mut TerrainRegion, params: &HydraulicErosion, seed: u32) {
+    if params.iterations == 0 {
+        return;
+    }
+
+    let spacing = TerrainRegion::spacing().x;
+    let original = region.heights.clone();
+    let mut eroded = original.clone();
+    for height in eroded.heights.iter_mut() {
+        *height /= spacing;
+    }
+
+    let chunks = region.max_chunk - region.min_chunk + IVec2::ONE;
+    let droplets = params.iterations * (chunks.x * chunks.y) as u32;
+    erode(&mut eroded, params, droplets, seed as u64);
+
+    for height in eroded.heights.iter_mut() {
+        *height *= spacing;
+    }
+    blend_into_region(region, &original, &eroded, params.fade);
+}
+
+/// Thermal weathering: wherever the drop to a neighbour is steeper than the
+/// talus angle, part of the excess slides down to it. Softens sharp peaks
+/// and cliff edges.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ThermalErosion {
+    /// Passes over the whole region; `0` disables weathering.
+    pub iterations: u32,
+    /// Steepest stable slope, in degrees from horizontal.
+    pub talus_angle: f32,
+    /// Fraction of the excess material moved per pass (0..1).
+    pub rate: f32,
+    /// Samples inside the region edge over which weathering fades out.
+    pub fade: usize,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 0,
+            talus_angle: 40.0,
+            rate: 0.5,
+            fade: 4,
+        }
+    }
+}
+
+const NEIGHBOURS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+pub fn weather(heights: &mut Heightfield, params: &ThermalErosion, spacing: f32) {
+    let talus = params.talus_angle.to_radians().tan();
+    let mut delta = vec![0.0; heights.heights.len()];
+
+    for _ in 0..params.iterations {
+        delta.fill(0.0);
+
+        for z in 0..heights.depth {
+            for x in 0..heights.width {
+                let height = heights.get(x, z);
+                let mut drops = [(0, 0.0); 8];
+                let mut total = 0.0;
+                let mut steepest = 0.0;
+
+                for (n, (dx, dz)) in NEIGHBOURS.iter().enumerate() {
+                    let (nx, nz) = (x as i32 + dx, z as i32 + dz);
+                    if nx < 0 || nz < 0 || nx >= heights.width as i32 || nz >= heights.depth as i32
+                    {
+                        continue;
+                    }
+                    let (nx, nz) = (nx as usize, nz as usize);
+                    let distance = spacing * ((dx * dx + dz * dz) as f32).sqrt();
+                    let excess = height - heights.get(nx, nz) - talus * distance;
+                    if excess > 0.0 {
+                        drops[n] = (heights.index(nx, nz), excess);
+                        total += excess;
+                        if excess > steepest {
+                            steepest = excess;
+                        }
+                    }
+                }
+
+                if total <= 0.0 {
+                    continue;
+                }
+
+                // move half the steepest excess (scaled by rate), shared out
+                // in proportion to how far each neighbour is over the limit
+                let moved = params.rate * steepest * 0.5;
+                delta[heights.index(x, z)] -= moved;
+                for (i, excess) in drops {
+                    if excess > 0.0 {
+                        delta[i] += moved * excess / total;
+                    }
+                }
+            }
+        }
+
+        for (height, delta) in heights.heights.iter_mut().zip(&delta) {
+            *height += delta;
+        }
+    }
+}
+
+pub fn weather_region(region: &mut TerrainRegion, params: &ThermalErosion) {
+    if params.iterations == 0 {
+        return;
+    }
+
+    let original = region.heights.clone();
+    let mut weathered = original.clone();
+    weather(&mut weathered, params, TerrainRegion::spacing().x);
+    blend_into_region(region, &original, &weathered, params.fade);
+}
@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+
+use crate::chunk_tasks::ChunkSource;
+use crate::heightfield::{TerrainRegion, TerrainSurface};
+use crate::heightmap::Heightmap;
+use crate::normals;
+use crate::streaming::chunk_at;
+use crate::terrain_settings::TerrainSettings;
+use crate::{QuadtreeTerrain, TerrainChunks, TerrainPalettes};
+
+/// The ground at a world (x, z).
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainSample {
+    pub height: f32,
+    pub normal: Vec3,
+    /// Linear colour of the palette band (or blend of biome bands) the
+    /// point falls in, as the terrain's vertices get it.
+    pub color: [f32; 4],
+}
+
+/// Tells surfaces built for different `QuerySurface::set` calls apart.
+static SURFACE_IDS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// This thread's surface, and the id of the source it was built from.
+    /// Only the latest source's is kept: a new one replaces it, and a
+    /// cleared source drops it on the next query.
+    static SURFACE: RefCell<Option<(u64, TerrainSurface)>> = const { RefCell::new(None) };
+}
+
+/// What the terrain's surface is built from, for points no generated
+/// region covers: the settings and the heightmap the generator loaded, if
+/// any. The noise isn't `Send`, so each thread that queries builds its own
+/// surface from these, once per change.
+#[derive(Resource, Default)]
+pub struct QuerySurface(Option<SurfaceSource>);
+
+struct SurfaceSource {
+    id: u64,
+    settings: Arc<TerrainSettings>,
+    heightmap: Option<Arc<Heightmap>>,
+}
+
+impl QuerySurface {
+    /// Surface of a terrain generated from `settings`, with `heightmap`
+    /// once it has loaded.
+    pub fn set(&mut self, settings: &TerrainSettings, heightmap: Option<Arc<Heightmap>>) {
+        self.0 = Some(SurfaceSource {
+            id: SURFACE_IDS.fetch_add(1, Ordering::Relaxed),
+            settings: Arc::new(settings.clone()),
+            heightmap,
+        });
+    }
+
+    /// No surface, while a new terrain's heightmap loads.
+    pub fn clear(&mut self) {
+        self.0 = None;
+    }
+
+    /// Runs `f` on this thread's copy of the surface, building it first if
+    /// the source changed since.
+    fn with<R>(&self, f: impl FnOnce(&TerrainSurface) -> R) -> Option<R> {
+        SURFACE.with_borrow_mut(|surface| {
+            let Some(source) = &self.0 else {
+                *surface = None;
+                return None;
+            };
+            if surface.as_ref().is_none_or(|(id, _)| *id != source.id) {
+                let (min_chunk, _) = source.settings.chunk_range(source.heightmap.as_deref());
+                let built =
+                    TerrainSurface::new(&source.settings, source.heightmap.clone(), min_chunk);
+                *surface = Some((source.id, built));
+            }
+            surface.as_ref().map(|(_, surface)| f(surface))
+        })
+    }
+}
+
+/// Height, normal and colour of the terrain anywhere. Points on a loaded
+/// chunk (or the quadtree's region) are interpolated from its eroded
+/// heights, exactly where its mesh puts them at full detail; anywhere else
+/// they're evaluated from the noise or heightmap, without erosion.
+#[derive(SystemParam)]
+pub struct TerrainHeightQuery<'w> {
+    settings: Res<'w, TerrainSettings>,
+    chunks: Res<'w, TerrainChunks>,
+    quadtree: Res<'w, QuadtreeTerrain>,
+    palettes: TerrainPalettes<'w>,
+    surface: Res<'w, QuerySurface>,
+}
+
+impl TerrainHeightQuery<'_> {
+    /// The generated region covering world `(x, z)`, if any.
+    fn region(&self, x: f32, z: f32) -> Option<&TerrainRegion> {
+        let region = match self.chunks.0.get(&chunk_at(Vec3::new(x, 0.0, z))) {
+            Some(loaded) => match &loaded.source {
+                ChunkSource::Region { region, .. } => region.as_ref(),
+                ChunkSource::Noise => return None,
+            },
+            None => self.quadtree.tree.as_ref()?.region(),
+        };
+        let sample = region.world_to_sample(Vec2::new(x, z));
+        let last = Vec2::new(
+            (region.heights.width - 1) as f32,
+            (region.heights.depth - 1) as f32,
+        );
+        (sample.cmpge(Vec2::ZERO).all() && sample.cmple(last).all()).then_some(region)
+    }
+
+    /// Height and normal at world `(x, z)`, or `None` while a heightmap
+    /// loads.
+    fn ground(&self, x: f32, z: f32) -> Option<(f32, Vec3)> {
+        if let Some(region) = self.region(x, z) {
+            let sample = region.world_to_sample(Vec2::new(x, z));
+            return Some((
+                region.heights.bilinear(sample),
+                normals::sample_normal_at(region, sample),
+            ));
+        }
+        let (x, z) = (x as f64, z as f64);
+        self.surface
+            .with(|surface| (surface.height(x, z), normals::surface_normal(surface, x, z)))
+    }
+
+    /// Ground height at world `(x, z)`; cheaper than `sample`.
+    pub fn height(&self, x: f32, z: f32) -> Option<f32> {
+        self.ground(x, z).map(|(height, _)| height)
+    }
+
+    /// Everything about the ground at world `(x, z)`, or `None` while the
+    /// palettes are still loading. Builds the colouring on every call, so
+    /// it's meant for the odd query rather than every vertex.
+    pub fn sample(&self, x: f32, z: f32) -> Option<TerrainSample> {
+        let (height, normal) = self.ground(x, z)?;
+        let coloring = self.palettes.coloring(&self.settings)?;
+        Some(TerrainSample {
+            height,
+            normal,
+            color: coloring.color(Vec3::new(x, height, z), normal, self.settings.height_scale),
+        })
+    }
+}
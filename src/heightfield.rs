@@ -0,0 +1,227 @@
+use std::sync::Arc;
+
+use bevy::math::DVec2;
+use bevy::prelude::*;
+
+use crate::biome::BiomeMap;
+use crate::heightmap::Heightmap;
+use crate::noise_stack::TerrainNoise;
+use crate::terrain_settings::TerrainSettings;
+use crate::{TERRAIN_CHUNK_H, TERRAIN_CHUNK_SUBDIVISIONS, TERRAIN_CHUNK_W};
+
+/// Vertices along one edge of a chunk, matching `Plane3d`'s subdivided mesh.
+pub const CHUNK_VERTICES: usize = TERRAIN_CHUNK_SUBDIVISIONS as usize + 2;
+/// Quads along one edge of a chunk.
+pub const CHUNK_QUADS: usize = CHUNK_VERTICES - 1;
+
+/// Row-major grid of heights, `width` samples along x and `depth` along z.
+#[derive(Clone, Debug)]
+pub struct Heightfield {
+    pub width: usize,
+    pub depth: usize,
+    pub heights: Vec<f32>,
+}
+
+impl Heightfield {
+    pub fn new(width: usize, depth: usize) -> Self {
+        Self {
+            width,
+            depth,
+            heights: vec![0.0; width * depth],
+        }
+    }
+
+    pub fn index(&self, x: usize, z: usize) -> usize {
+        z * self.width + x
+    }
+
+    pub fn get(&self, x: usize, z: usize) -> f32 {
+        self.heights[self.index(x, z)]
+    }
+
+    pub fn set(&mut self, x: usize, z: usize, height: f32) {
+        let i = self.index(x, z);
+        self.heights[i] = height;
+    }
+
+    /// Height at fractional sample coordinates, interpolated between the
+    /// four surrounding samples.
+    pub fn bilinear(&self, pos: Vec2) -> f32 {
+        let (x0, z0, x1, z1, u, v) = self.cell(pos);
+        let top = self.get(x0, z0) * (1.0 - u) + self.get(x1, z0) * u;
+        let bottom = self.get(x0, z1) * (1.0 - u) + self.get(x1, z1) * u;
+        top * (1.0 - v) + bottom * v
+    }
+
+    /// Corner samples of the cell containing `pos` (clamped to the grid)
+    /// and `pos`'s offset within it.
+    pub fn cell(&self, pos: Vec2) -> (usize, usize, usize, usize, f32, f32) {
+        let pos = pos.clamp(
+            Vec2::ZERO,
+            Vec2::new((self.width - 1) as f32, (self.depth - 1) as f32),
+        );
+        let x0 = pos.x.floor() as usize;
+        let z0 = pos.y.floor() as usize;
+        (
+            x0,
+            z0,
+            (x0 + 1).min(self.width - 1),
+            (z0 + 1).min(self.depth - 1),
+            pos.x - x0 as f32,
+            pos.y - z0 as f32,
+        )
+    }
+}
+
+/// Heights for a rectangle of chunks plus an `apron` of extra samples on
+/// every side. Chunks that share an edge read the same samples, so the
+/// region is seamless by construction; the apron gives post-noise passes
+/// (erosion) room to run past the outermost chunk edges.
+#[derive(Clone, Debug)]
+pub struct TerrainRegion {
+    pub min_chunk: IVec2,
+    pub max_chunk: IVec2,
+    pub apron: usize,
+    pub heights: Heightfield,
+}
+
+impl TerrainRegion {
+    pub fn new(min_chunk: IVec2, max_chunk: IVec2, apron: usize) -> Self {
+        let chunks = max_chunk - min_chunk + IVec2::ONE;
+        let width = chunks.x as usize * CHUNK_QUADS + 1 + 2 * apron;
+        let depth = chunks.y as usize * CHUNK_QUADS + 1 + 2 * apron;
+        Self {
+            min_chunk,
+            max_chunk,
+            apron,
+            heights: Heightfield::new(width, depth),
+        }
+    }
+
+    /// Distance between neighbouring samples in world units.
+    pub fn spacing() -> Vec2 {
+        Vec2::new(TERRAIN_CHUNK_W, TERRAIN_CHUNK_H) / CHUNK_QUADS as f32
+    }
+
+    /// World (x, z) of sample `(x, z)`.
+    pub fn world_position(&self, x: usize, z: usize) -> DVec2 {
+        self.world_position_at(Vec2::new(x as f32, z as f32))
+    }
+
+    /// World (x, z) at fractional sample coordinates.
+    pub fn world_position_at(&self, sample: Vec2) -> DVec2 {
+        let chunk = DVec2::new(TERRAIN_CHUNK_W as f64, TERRAIN_CHUNK_H as f64);
+        let spacing = chunk / CHUNK_QUADS as f64;
+        let first = self.min_chunk.as_dvec2() * chunk - chunk / 2.0;
+        let offset = sample.as_dvec2() - DVec2::splat(self.apron as f64);
+        first + offset * spacing
+    }
+
+    /// Fractional sample coordinates at world `(x, z)`; the inverse of
+    /// `world_position_at`.
+    pub fn world_to_sample(&self, world: Vec2) -> Vec2 {
+        let first = self.world_position_at(Vec2::ZERO).as_vec2();
+        (world - first) / Self::spacing()
+    }
+
+    /// World (x, z) of the first vertex of `min_chunk`.
+    pub fn world_min(&self) -> DVec2 {
+        self.world_position(self.apron, self.apron)
+    }
+
+    /// Fills every sample with `height(world_x, world_z)`.
+    pub fn fill(&mut self, height: impl Fn(f64, f64) -> f32) {
+        for z in 0..self.heights.depth {
+            for x in 0..self.heights.width {
+                let world = self.world_position(x, z);
+                self.heights.set(x, z, height(world.x, world.y));
+            }
+        }
+    }
+
+    /// Fills the region from a continuous height function.
+    pub fn sample(&mut self, surface: &TerrainSurface) {
+        self.fill(|x, z| surface.height(x, z));
+    }
+
+    /// Sample coordinates of vertex `(x, z)` of `chunk`.
+    pub fn chunk_sample(&self, chunk: IVec2, x: usize, z: usize) -> (usize, usize) {
+        let offset = (chunk - self.min_chunk).as_uvec2();
+        (
+            offset.x as usize * CHUNK_QUADS + x + self.apron,
+            offset.y as usize * CHUNK_QUADS + z + self.apron,
+        )
+    }
+
+    /// Fractional sample coordinates of vertex `(x, z)` of a `chunk` mesh
+    /// with `vertices` along each edge instead of `CHUNK_VERTICES`.
+    pub fn chunk_sample_at(&self, chunk: IVec2, x: usize, z: usize, vertices: usize) -> Vec2 {
+        let step = CHUNK_QUADS as f32 / (vertices - 1) as f32;
+        let (first_x, first_z) = self.chunk_sample(chunk, 0, 0);
+        Vec2::new(
+            first_x as f32 + x as f32 * step,
+            first_z as f32 + z as f32 * step,
+        )
+    }
+}
+
+/// The continuous height function a region is sampled from, before any
+/// erosion. Unlike the region's samples it can be evaluated anywhere.
+pub enum TerrainSurface {
+    Noise {
+        noise: Box<TerrainNoise>,
+        height_scale: f32,
+        /// Reshapes the noise per biome.
+        biomes: Option<Box<BiomeMap>>,
+    },
+    /// An imported heightmap with its first sample at world `origin`,
+    /// shared with every chunk task meshing from it.
+    Heightmap {
+        heightmap: Arc<Heightmap>,
+        origin: DVec2,
+    },
+}
+
+impl TerrainSurface {
+    /// The settings' noise stack, or `heightmap` laid out from the first
+    /// vertex of `min_chunk`.
+    pub fn new(
+        settings: &TerrainSettings,
+        heightmap: Option<Arc<Heightmap>>,
+        min_chunk: IVec2,
+    ) -> Self {
+        match heightmap {
+            Some(heightmap) => Self::Heightmap {
+                heightmap,
+                origin: TerrainRegion::new(min_chunk, min_chunk, 0).world_min(),
+            },
+            None => Self::Noise {
+                noise: Box::new(TerrainNoise::new(settings)),
+                height_scale: settings.height_scale,
+                biomes: BiomeMap::new(settings).map(Box::new),
+            },
+        }
+    }
+
+    /// World height at world `(x, z)`.
+    pub fn height(&self, x: f64, z: f64) -> f32 {
+        match self {
+            // main terrain topology, plus any detail layers, doubled
+            TerrainSurface::Noise {
+                noise,
+                height_scale,
+                biomes,
+            } => {
+                let height = noise.sample(x, z) * height_scale * 2.0;
+                match biomes {
+                    Some(biomes) => biomes.modify_height(x, z, height),
+                    None => height,
+                }
+            }
+            // imported heights are already in world units
+            TerrainSurface::Heightmap { heightmap, origin } => {
+                heightmap.sample(x - origin.x, z - origin.y)
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+use std::fmt;
+use std::path::Path;
+
+use bevy::prelude::*;
+use image::{DynamicImage, ImageBuffer, Luma, Rgb};
+use serde::{Deserialize, Serialize};
+
+use crate::heightfield::{Heightfield, TerrainRegion};
+use crate::normals;
+use crate::TERRAIN_HEIGHT;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum HeightmapFormat {
+    /// 8-bit or 16-bit grayscale PNG.
+    #[default]
+    Png,
+    /// Headerless unsigned 16-bit samples, row by row.
+    RawR16 {
+        width: usize,
+        height: usize,
+        #[serde(default)]
+        endian: Endian,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HeightmapImport {
+    pub path: String,
+    pub format: HeightmapFormat,
+    /// World height between the lowest and highest possible sample value.
+    pub vertical_scale: f32,
+    /// World height of the lowest possible sample value.
+    pub height_offset: f32,
+    /// World size (x, z) the image is stretched over.
+    pub extent: [f32; 2],
+}
+
+impl Default for HeightmapImport {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            format: HeightmapFormat::Png,
+            // same range the noise terrain covers after its 2x height pass
+            vertical_scale: TERRAIN_HEIGHT * 4.0,
+            height_offset: -TERRAIN_HEIGHT * 2.0,
+            extent: [2560.0, 2560.0],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HeightmapError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    RawSize { expected: usize, actual: usize },
+    Empty,
+    Ron(ron::Error),
+}
+
+impl fmt::Display for HeightmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeightmapError::Io(err) => write!(f, "{err}"),
+            HeightmapError::Image(err) => write!(f, "{err}"),
+            HeightmapError::RawSize { expected, actual } => {
+                write!(f, "raw heightmap is {actual} bytes, expected {expected}")
+            }
+            HeightmapError::Empty => write!(f, "heightmap has no samples"),
+            HeightmapError::Ron(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for HeightmapError {}
+
+impl From<std::io::Error> for HeightmapError {
+    fn from(err: std::io::Error) -> Self {
+        HeightmapError::Io(err)
+    }
+}
+
+impl From<ron::Error> for HeightmapError {
+    fn from(err: ron::Error) -> Self {
+        HeightmapError::Ron(err)
+    }
+}
+
+impl From<image::ImageError> for HeightmapError {
+    fn from(err: image::ImageError) -> Self {
+        HeightmapError::Image(err)
+    }
+}
+
+/// Imported heights in world units, stretched over `extent` with sample
+/// (0, 0) at the local origin.
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    pub heights: Heightfield,
+    pub extent: Vec2,
+}
+
+impl Heightmap {
+    pub fn load(import: &HeightmapImport) -> Result<Self, HeightmapError> {
+        let mut heights = match &import.format {
+            HeightmapFormat::Png => png_heights(image::open(&import.path)?)?,
+            HeightmapFormat::RawR16 {
+                width,
+                height,
+                endian,
+            } => raw_r16_heights(&std::fs::read(&import.path)?, *width, *height, *endian)?,
+        };
+
+        for h in heights.heights.iter_mut() {
+            *h = *h * import.vertical_scale + import.height_offset;
+        }
+
+        Ok(Self {
+            heights,
+            extent: Vec2::from(import.extent),
+        })
+    }
+
+    /// Bilinearly interpolated height at `(x, z)` relative to the first
+    /// sample; positions outside the extent repeat the nearest edge sample.
+    pub fn sample(&self, x: f64, z: f64) -> f32 {
+        let max = Vec2::new(
+            (self.heights.width - 1) as f32,
+            (self.heights.depth - 1) as f32,
+        );
+        let uv = (Vec2::new(x as f32, z as f32) / self.extent).clamp(Vec2::ZERO, Vec2::ONE);
+        self.heights.bilinear(uv * max)
+    }
+}
+
+/// Normalised `0.0..=1.0` samples from a decoded 8/16-bit grayscale PNG.
+/// Colour images are converted to luma rather than rejected.
+fn png_heights(image: DynamicImage) -> Result<Heightfield, HeightmapError> {
+    let (width, depth) = (image.width() as usize, image.height() as usize);
+    if width == 0 || depth == 0 {
+        return Err(HeightmapError::Empty);
+    }
+
+    let mut heights = Heightfield::new(width, depth);
+    match image {
+        DynamicImage::ImageLuma8(gray) => {
+            for (h, p) in heights.heights.iter_mut().zip(gray.pixels()) {
+                *h = p.0[0] as f32 / u8::MAX as f32;
+            }
+        }
+        image => {
+            for (h, p) in heights.heights.iter_mut().zip(image.into_luma16().pixels()) {
+                *h = p.0[0] as f32 / u16::MAX as f32;
+            }
+        }
+    }
+    Ok(heights)
+}
+
+/// Normalised `0.0..=1.0` samples from headerless 16-bit `bytes`.
+fn raw_r16_heights(
+    bytes: &[u8],
+    width: usize,
+    depth: usize,
+    endian: Endian,
+) -> Result<Heightfield, HeightmapError> {
+    if width == 0 || depth == 0 {
+        return Err(HeightmapError::Empty);
+    }
+
+    let expected = width * depth * 2;
+    if bytes.len() != expected {
+        return Err(HeightmapError::RawSize {
+            expected,
+            actual: bytes.len(),
+        });
+    }
+
+    let mut heights = Heightfield::new(width, depth);
+    for (h, pair) in heights.heights.iter_mut().zip(bytes.chunks_exact(2)) {
+        let pair = [pair[0], pair[1]];
+        let value = match endian {
+            Endian::Little => u16::from_le_bytes(pair),
+            Endian::Big => u16::from_be_bytes(pair),
+        };
+        *h = value as f32 / u16::MAX as f32;
+    }
+    Ok(heights)
+}
+
+/// Writes the chunk area of `region` (without its apron) to `dir`:
+///
+/// - `heightmap.png`: 16-bit grayscale, lowest height black, highest white
+/// - `heightmap.r16`: the same samples as little-endian RAW r16
+/// - `heightmap.ron`: a `HeightmapImport` that loads them back 1:1
+/// - `colormap.png`: 8-bit RGB from `color(world_position, normal)`, the
+///   same linear vertex colours the chunk meshes get
+pub fn export(
+    region: &TerrainRegion,
+    dir: impl AsRef<Path>,
+    color: impl Fn(Vec3, Vec3) -> [f32; 4],
+) -> Result<(), HeightmapError> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+
+    let apron = region.apron;
+    let width = region.heights.width - 2 * apron;
+    let depth = region.heights.depth - 2 * apron;
+    let height = |x: u32, z: u32| region.heights.get(x as usize + apron, z as usize + apron);
+
+    // over the samples written, so they span black to white
+    let (min, max) = (0..depth as u32)
+        .flat_map(|z| (0..width as u32).map(move |x| (x, z)))
+        .map(|(x, z)| height(x, z))
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), h| {
+            (min.min(h), max.max(h))
+        });
+    let range = (max - min).max(f32::EPSILON);
+    let quantize = |h: f32| ((h - min) / range * u16::MAX as f32).round() as u16;
+
+    let gray = ImageBuffer::from_fn(width as u32, depth as u32, |x, z| {
+        Luma([quantize(height(x, z))])
+    });
+    gray.save(dir.join("heightmap.png"))?;
+
+    let raw: Vec<u8> = gray.pixels().flat_map(|p| p.0[0].to_le_bytes()).collect();
+    std::fs::write(dir.join("heightmap.r16"), raw)?;
+
+    let normal =
+        |x: u32, z: u32| normals::sample_normal(region, x as usize + apron, z as usize + apron);
+
+    let position = |x: u32, z: u32| {
+        let world = region.world_position(x as usize + apron, z as usize + apron);
+        Vec3::new(world.x as f32, height(x, z), world.y as f32)
+    };
+
+    let colors = ImageBuffer::from_fn(width as u32, depth as u32, |x, z| {
+        let srgb = Srgba::from(LinearRgba::from_f32_array(color(
+            position(x, z),
+            normal(x, z),
+        )));
+        Rgb(srgb.to_u8_array_no_alpha())
+    });
+    colors.save(dir.join("colormap.png"))?;
+
+    let spacing = TerrainRegion::spacing();
+    let import = HeightmapImport {
+        path: dir.join("heightmap.png").to_string_lossy().into_owned(),
+        format: HeightmapFormat::Png,
+        vertical_scale: range,
+        height_offset: min,
+        extent: [
+            (width - 1) as f32 * spacing.x,
+            (depth - 1) as f32 * spacing.y,
+        ],
+    };
+    let config = ron::ser::to_string_pretty(&import, ron::ser::PrettyConfig::default())?;
+    std::fs::write(dir.join("heightmap.ron"), config)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_spans_the_written_samples_and_loads_back() {
+        let mut region = TerrainRegion::new(IVec2::ZERO, IVec2::ZERO, 2);
+        region.fill(|x, z| ((x * 0.013).sin() * 40.0 + (z * 0.007).cos() * 25.0) as f32);
+        // extremes in the apron, which isn't written
+        region.heights.set(0, 0, 1000.0);
+        region.heights.set(1, 0, -1000.0);
+
+        let dir = std::env::temp_dir().join(format!("terrain_export_{}", std::process::id()));
+        export(&region, &dir, |_, _| [1.0; 4]).unwrap();
+        let config = std::fs::read_to_string(dir.join("heightmap.ron")).unwrap();
+        let loaded = Heightmap::load(&ron::from_str(&config).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let apron = region.apron;
+        let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+        for z in 0..loaded.heights.depth {
+            for x in 0..loaded.heights.width {
+                let h = region.heights.get(x + apron, z + apron);
+                (min, max) = (min.min(h), max.max(h));
+            }
+        }
+        let step = (max - min) / u16::MAX as f32;
+        for z in 0..loaded.heights.depth {
+            for x in 0..loaded.heights.width {
+                let expected = region.heights.get(x + apron, z + apron);
+                let actual = loaded.heights.get(x, z);
+                assert!((actual - expected).abs() <= step, "{actual} vs {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn png_samples_span_zero_to_one() {
+        let gray8 = ImageBuffer::from_fn(3, 2, |x, z| {
+            Luma([[[0, 51, 255], [1, 2, 3]][z as usize][x as usize]])
+        });
+        let heights = png_heights(DynamicImage::ImageLuma8(gray8)).unwrap();
+        assert_eq!((heights.width, heights.depth), (3, 2));
+        assert_eq!(heights.get(0, 0), 0.0);
+        assert_eq!(heights.get(1, 0), 0.2);
+        assert_eq!(heights.get(2, 0), 1.0);
+        assert_eq!(heights.get(0, 1), 1.0 / 255.0);
+
+        let gray16 = ImageBuffer::from_fn(2, 1, |x, _| Luma([[0, u16::MAX][x as usize]]));
+        let heights = png_heights(DynamicImage::ImageLuma16(gray16)).unwrap();
+        assert_eq!(heights.get(0, 0), 0.0);
+        assert_eq!(heights.get(1, 0), 1.0);
+
+        // through the encoder, so the bit depth survives the file
+        let gray16 = ImageBuffer::from_fn(2, 2, |x, z| Luma([(x * 0x1234 + z * 0x8000) as u16]));
+        let mut png = Vec::new();
+        DynamicImage::ImageLuma16(gray16)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let heights = png_heights(image::load_from_memory(&png).unwrap()).unwrap();
+        assert_eq!(heights.get(1, 1), 0x9234 as f32 / u16::MAX as f32);
+    }
+
+    #[test]
+    fn raw_r16_reads_either_byte_order() {
+        let bytes = [0x12, 0x34, 0xff, 0xff, 0x00, 0x00];
+        let little = raw_r16_heights(&bytes, 3, 1, Endian::Little).unwrap();
+        let big = raw_r16_heights(&bytes, 3, 1, Endian::Big).unwrap();
+        assert_eq!(little.get(0, 0), 0x3412 as f32 / u16::MAX as f32);
+        assert_eq!(big.get(0, 0), 0x1234 as f32 / u16::MAX as f32);
+        for heights in [little, big] {
+            assert_eq!(heights.get(1, 0), 1.0);
+            assert_eq!(heights.get(2, 0), 0.0);
+        }
+        assert!(matches!(
+            raw_r16_heights(&bytes, 2, 2, Endian::Little),
+            Err(HeightmapError::RawSize {
+                expected: 8,
+                actual: 6
+            })
+        ));
+    }
+}
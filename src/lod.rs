@@ -0,0 +1,87 @@
+use serde::Deserialize;
+
+use crate::seams::SeamFix;
+use crate::TERRAIN_CHUNK_SUBDIVISIONS;
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct LodLevel {
+    /// Camera distance in world units (to the chunk's centre at sea level)
+    /// from which this level is used.
+    pub distance: f32,
+    /// `Plane3d` subdivisions of the chunk mesh. Heights between the
+    /// region's samples are interpolated, so more than
+    /// `TERRAIN_CHUNK_SUBDIVISIONS` adds vertices but no detail.
+    pub subdivisions: u32,
+}
+
+/// Mesh detail by distance from the camera. Level 0 is the finest.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Lod {
+    /// In order of increasing `distance`; empty meshes every chunk at
+    /// `TERRAIN_CHUNK_SUBDIVISIONS`.
+    pub levels: Vec<LodLevel>,
+    /// How far past a threshold a chunk has to move before it switches
+    /// level, so hovering around one doesn't keep swapping meshes.
+    pub hysteresis: f32,
+    /// How cracks between chunks at different levels are hidden.
+    pub seams: SeamFix,
+}
+
+/// LOD levels a chunk mesh is built for: its own, and its neighbours' in
+/// `NEIGHBOURS` order for stitching to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChunkDetail {
+    pub lod: usize,
+    pub neighbours: [usize; 4],
+}
+
+impl Default for Lod {
+    fn default() -> Self {
+        let level = |distance, subdivisions| LodLevel {
+            distance,
+            subdivisions,
+        };
+        Self {
+            levels: vec![
+                level(0.0, TERRAIN_CHUNK_SUBDIVISIONS),
+                level(1000.0, TERRAIN_CHUNK_SUBDIVISIONS / 2),
+                level(2000.0, TERRAIN_CHUNK_SUBDIVISIONS / 4),
+            ],
+            hysteresis: 100.0,
+            seams: SeamFix::default(),
+        }
+    }
+}
+
+impl Lod {
+    /// Level for a chunk `distance` from the camera that's currently at
+    /// `current`, if it has a mesh yet.
+    pub fn level(&self, distance: f32, current: Option<usize>) -> usize {
+        let level_at = |distance: f32| {
+            self.levels
+                .iter()
+                .rposition(|level| distance >= level.distance)
+                .unwrap_or(0)
+        };
+        let Some(current) = current else {
+            return level_at(distance);
+        };
+        // only switch once past the threshold by `hysteresis`, either way
+        let coarser = level_at(distance - self.hysteresis);
+        let finer = level_at(distance + self.hysteresis);
+        if coarser > current {
+            coarser
+        } else if finer < current {
+            finer
+        } else {
+            current
+        }
+    }
+
+    pub fn subdivisions(&self, level: usize) -> u32 {
+        self.levels
+            .get(level)
+            .map_or(TERRAIN_CHUNK_SUBDIVISIONS, |level| level.subdivisions)
+    }
+}
@@ -1,12 +1,15 @@
 // #[warn(unused_imports)]
 use std::f32::consts::PI;
+use std::sync::Arc;
 
-use bevy::color::palettes::css::WHITE;
 // use bevy::core_pipeline::oit::OrderIndependentTransparencySettings;
+use bevy::asset::LoadState;
+use bevy::diagnostic::Diagnostics;
 use bevy::diagnostic::EntityCountDiagnosticsPlugin;
 use bevy::diagnostic::FrameTimeDiagnosticsPlugin;
 use bevy::diagnostic::LogDiagnosticsPlugin;
 use bevy::diagnostic::SystemInformationDiagnosticsPlugin;
+use bevy::ecs::system::SystemParam;
 use bevy::pbr::CascadeShadowConfig;
 // use bevy::render::renderer::{RenderDevice, RenderQueue};
 
@@ -20,7 +23,6 @@ use bevy::{
 };
 
 use bevy::{
-    color::palettes::tailwind::*,
     // core_pipeline::{
     //     bloom::BloomSettings,
     //     dof::{DepthOfFieldMode, DepthOfFieldSettings},
@@ -30,22 +32,24 @@ use bevy::{
     // },
     pbr::{
         wireframe::{Wireframe, WireframePlugin},
+        NotShadowCaster,
         // CascadeShadowConfigBuilder, ExtendedMaterial, OpaqueRendererMethod,
     },
     render::{
         mesh::VertexAttributeValues,
         settings::{RenderCreation, WgpuFeatures, WgpuSettings},
+        view::NoFrustumCulling,
         RenderPlugin,
     },
 };
 // use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
 // use noise::{BasicMulti, NoiseFn, Perlin, Seedable};
 // use bevy::prelude::*;
-use noise::{BasicMulti, NoiseFn, Perlin};
 
 // https://bevy-cheatbook.github.io/setup/perf.html
 use bevy::core::TaskPoolThreadAssignmentPolicy;
 use bevy::tasks::available_parallelism;
+use bevy::utils::{Duration, HashMap, Instant};
 
 // https://github.com/bevyengine/bevy/blob/main/docs/profiling.md#gpu-runtime
 // https://github.com/bevyengine/bevy/blob/latest/docs/profiling.md
@@ -57,6 +61,45 @@ use bevy::tasks::available_parallelism;
 // use bevy_fps_counter::FpsCounterPlugin;
 // mod water_material;
 // use water_material::*;
+mod biome;
+mod chunk_cache;
+mod chunk_tasks;
+mod clipmap;
+mod dem;
+mod erosion;
+mod height_query;
+mod heightfield;
+mod heightmap;
+mod lod;
+mod noise_stack;
+mod normals;
+mod palette;
+mod quadtree;
+mod seams;
+mod streaming;
+mod terrain_diagnostics;
+mod terrain_material;
+mod terrain_settings;
+use biome::{BiomeMap, TerrainColoring};
+use chunk_cache::{ChunkCache, ChunkSamples};
+use chunk_tasks::{ChunkMesh, ChunkSource, ChunkTasks, GeneratedChunk, GeneratedGrid, StageTimes};
+use clipmap::Clipmap;
+use height_query::{QuerySurface, TerrainHeightQuery};
+use heightfield::{TerrainRegion, TerrainSurface};
+use lod::ChunkDetail;
+use normals::{NormalMode, ShadingMode};
+use palette::{TerrainPalette, TerrainPalettePlugin};
+use quadtree::{NodeKey, Quadtree, QuadtreeMaterial, TerrainMode};
+use seams::{SeamCost, SeamFix, NEIGHBOURS};
+use streaming::{fly_camera, FlyCamera};
+use terrain_diagnostics::{
+    TerrainDiagnosticsPlugin, CHUNK_GENERATION_TIME, CHUNK_LATENCY, CHUNK_TASKS, CLIPMAP_SAMPLES,
+    CLIPMAP_TIME, EROSION_TIME, LOADED_CHUNKS, LOD_TRIANGLES, NORMALS_TIME, QUADTREE_NODES,
+    QUADTREE_TIME, QUEUED_CHUNKS, SEAM_TRIANGLES, SEAM_VERTICES, TERRAIN_GENERATION_TIME,
+    TERRAIN_MESH_BYTES, TERRAIN_VERTICES, THERMAL_EROSION_TIME,
+};
+use terrain_material::{mesh_bytes, SharedGrids, TerrainMaterial};
+use terrain_settings::{TerrainSettings, TerrainSettingsPlugin};
 
 // text::FontSmoothing
 
@@ -78,6 +121,7 @@ use bevy::tasks::available_parallelism;
 // }
 
 fn main() {
+    #[allow(clippy::default_constructed_unit_structs)]
     App::new()
         .add_plugins((DefaultPlugins
             .set(RenderPlugin {
@@ -105,8 +149,17 @@ fn main() {
                         // set the minimum # of compute threads
                         // to the total number of available threads
                         min_threads: available_parallelism(),
+                        #[allow(clippy::legacy_numeric_constants)]
                         max_threads: std::usize::MAX, // unlimited max threads
-                        percent: 50.0,                // this value is irrelevant in this case
+                        percent: 50.0, // this value is irrelevant in this case
+                    },
+                    // chunk generation tasks; at least two threads so a
+                    // long erosion pass doesn't hold up every other chunk
+                    async_compute: TaskPoolThreadAssignmentPolicy {
+                        min_threads: 2,
+                        #[allow(clippy::legacy_numeric_constants)]
+                        max_threads: std::usize::MAX,
+                        percent: 50.0,
                     },
                     // keep the defaults for everything else
                     ..default()
@@ -123,10 +176,40 @@ fn main() {
         .add_plugins(LogDiagnosticsPlugin::default())
         .add_plugins(FrameTimeDiagnosticsPlugin::default())
         // we want Bevy to measure these values for us:
-        .add_plugins(EntityCountDiagnosticsPlugin::default())
-        .add_plugins(SystemInformationDiagnosticsPlugin::default())
+        .add_plugins(EntityCountDiagnosticsPlugin)
+        .add_plugins(SystemInformationDiagnosticsPlugin)
+        .add_plugins(TerrainDiagnosticsPlugin)
+        .add_plugins(TerrainPalettePlugin)
+        .add_plugins(TerrainSettingsPlugin)
+        .add_plugins(MaterialPlugin::<TerrainMaterial>::default())
+        .add_plugins(MaterialPlugin::<QuadtreeMaterial>::default())
+        .init_resource::<TerrainRebuild>()
+        .init_resource::<BiomeDebug>()
+        .init_resource::<TerrainChunks>()
+        .init_resource::<ChunkTasks>()
+        .init_resource::<QuadtreeTerrain>()
+        .init_resource::<QuerySurface>()
+        .init_resource::<SharedGrids>()
+        .init_non_send_resource::<ClipmapTerrain>()
         .add_systems(Startup, startup)
-        .add_systems(Update, toggle_wireframe)
+        .add_systems(
+            Update,
+            (
+                (reload_terrain, toggle_biome_debug),
+                spawn_terrain,
+                finish_chunk_tasks,
+                (
+                    recolor_terrain,
+                    stream_chunks,
+                    update_chunk_lod,
+                    update_quadtree,
+                    update_clipmap,
+                ),
+                start_chunk_tasks,
+            )
+                .chain(),
+        )
+        .add_systems(Update, (toggle_wireframe, fly_camera, log_ground))
         // .add_systems(Update, debug_transform)
         // .add_plugins(FpsCounterPlugin)
         // .add_systems(Startup, debug_renderes)
@@ -144,113 +227,227 @@ const TERRAIN_CHUNK_H: f32 = 1024.0 / TERRAIN_XZ_TO_Y_SCALLER;
 const TERRAIN_CHUNK_SUBDIVISIONS: u32 = 32 / TERRAIN_XZ_TO_Y_SCALLER as u32;
 const TERRAIN_CHUNK_SCALLER: f64 = 300.0;
 
+/// Samples the noise stack over a rectangle of chunks and runs the
+/// post-noise passes on the shared heightfield.
+fn generate_region(
+    settings: &TerrainSettings,
+    min_chunk: IVec2,
+    max_chunk: IVec2,
+    surface: &TerrainSurface,
+    times: &mut StageTimes,
+) -> TerrainRegion {
+    // at least one sample of apron so edge normals can see past the border
+    let apron = settings.erosion.overlap.max(1);
+    let mut region = TerrainRegion::new(min_chunk, max_chunk, apron);
+    region.sample(surface);
+
+    let erosion_started = Instant::now();
+    erosion::erode_region(&mut region, &settings.erosion, settings.seed);
+    if settings.erosion.iterations > 0 {
+        let erosion_time = erosion_started.elapsed();
+        info!(
+            "hydraulic erosion: {} droplets/chunk in {:.1}ms",
+            settings.erosion.iterations,
+            erosion_time.as_secs_f64() * 1000.0
+        );
+        times.erosion = Some(erosion_time);
+    }
+
+    let weathering_started = Instant::now();
+    erosion::weather_region(&mut region, &settings.thermal);
+    if settings.thermal.iterations > 0 {
+        let weathering_time = weathering_started.elapsed();
+        info!(
+            "thermal erosion: {} passes at {} degrees in {:.1}ms",
+            settings.thermal.iterations,
+            settings.thermal.talus_angle,
+            weathering_time.as_secs_f64() * 1000.0
+        );
+        times.weathering = Some(weathering_time);
+    }
+
+    region
+}
+
+/// `generate_region`, or the region rebuilt from the chunk cache if every
+/// chunk in it is cached there, skipping the noise and erosion. Also
+/// returns each chunk's samples, or none when the cache is off.
+fn cached_region(
+    settings: &TerrainSettings,
+    coloring: &TerrainColoring,
+    min_chunk: IVec2,
+    max_chunk: IVec2,
+    surface: &TerrainSurface,
+    times: &mut StageTimes,
+) -> (TerrainRegion, HashMap<IVec2, Arc<ChunkSamples>>) {
+    let Some(cache) = ChunkCache::new(settings, min_chunk, max_chunk) else {
+        let region = generate_region(settings, min_chunk, max_chunk, surface, times);
+        return (region, HashMap::new());
+    };
+    if let Some(cached) = cache.load_region(settings, coloring, min_chunk, max_chunk) {
+        debug!("loaded chunks {min_chunk}..={max_chunk} from the chunk cache");
+        let region = chunk_cache::region_from_samples(min_chunk, max_chunk, &cached);
+        return (region, cached);
+    }
+    let region = generate_region(settings, min_chunk, max_chunk, surface, times);
+    let samples = cache.store_region(settings, &region, coloring);
+    (region, samples)
+}
+
+/// Loads or samples the heights for the settings' fixed chunk grid and runs
+/// the post-noise passes over all of it, exporting the result when
+/// `TERRAIN_EXPORT_DIR` is set. Runs on a background task.
+fn generate_grid(settings: &TerrainSettings, coloring: &TerrainColoring) -> GeneratedGrid {
+    let heightmap = settings.load_heightmap().map(Arc::new);
+    let (min_chunk, max_chunk) = settings.chunk_range(heightmap.as_deref());
+    let surface = TerrainSurface::new(settings, heightmap.clone(), min_chunk);
+    let mut times = StageTimes::default();
+    let (region, samples) = cached_region(
+        settings, coloring, min_chunk, max_chunk, &surface, &mut times,
+    );
+
+    if let Ok(dir) = std::env::var("TERRAIN_EXPORT_DIR") {
+        match heightmap::export(&region, &dir, |position, normal| {
+            coloring.color(position, normal, settings.height_scale)
+        }) {
+            Ok(()) => info!("exported terrain heightmap and colour map to {dir:?}"),
+            Err(err) => error!("failed to export terrain to {dir:?}: {err}"),
+        }
+    }
+
+    GeneratedGrid {
+        region: Arc::new(region),
+        heightmap,
+        samples,
+        times,
+    }
+}
+
 fn generate_chunk(
     // mut commands: Commands,
     // mut meshes: ResMut<Assets<Mesh>>,
     // mut materials: ResMut<Assets<StandardMaterial>>,
-    x: f64,
-    z: f64,
-) -> Mesh {
-    let noise: BasicMulti<Perlin> = BasicMulti::<Perlin>::default();
-
+    settings: &TerrainSettings,
+    region: &TerrainRegion,
+    surface: &TerrainSurface,
+    coloring: &TerrainColoring,
+    chunk: IVec2,
+    detail: ChunkDetail,
+    normals_time: &mut Duration,
+) -> (Mesh, SeamCost) {
+    let subdivisions = settings.lod.subdivisions(detail.lod);
+    let vertices = subdivisions as usize + 2;
     let mut terrain = Mesh::from(
         Plane3d::default()
             .mesh()
             // .size(1000.0, 1000.0)
             // .subdivisions(20),
             .size(TERRAIN_CHUNK_W, TERRAIN_CHUNK_H)
-            .subdivisions(TERRAIN_CHUNK_SUBDIVISIONS),
+            .subdivisions(subdivisions),
     );
 
+    // where each vertex sits in the region, in mesh order
+    let mut samples: Vec<Vec2> = (0..vertices * vertices)
+        .map(|i| region.chunk_sample_at(chunk, i % vertices, i / vertices, vertices))
+        .collect();
+    if settings.lod.seams == SeamFix::Stitch {
+        let edges = detail
+            .neighbours
+            .map(|lod| settings.lod.subdivisions(lod) as usize + 2);
+        seams::stitch(&mut samples, vertices, edges);
+    }
+
     if let Some(VertexAttributeValues::Float32x3(positions)) =
         terrain.attribute_mut(Mesh::ATTRIBUTE_POSITION)
     {
-        // main terrain topology
-        for pos in positions.iter_mut() {
-            let xi: f32 = noise.get([
-                (pos[0] as f64 + (TERRAIN_CHUNK_W as f64 * x)) as f64 / TERRAIN_CHUNK_SCALLER,
-                (pos[2] as f64 + (TERRAIN_CHUNK_H as f64 * z)) as f64 / TERRAIN_CHUNK_SCALLER,
-                0. as f64,
-            ]) as f32;
-            pos[0] += (TERRAIN_CHUNK_W * x as f32) as f32;
-            pos[1] = xi * TERRAIN_HEIGHT * 1.0;
-            pos[2] += (TERRAIN_CHUNK_H * z as f32) as f32;
-        }
-
-        // seconds pass
-        // for pos in positions.iter_mut() {
-        //     let xi: f32 = noise.get([
-        //         pos[0] as f64 / (TERRAIN_CHUNK_SCALLER * 0.1) + (TERRAIN_CHUNK_SCALLER * x),
-        //         pos[2] as f64 / (TERRAIN_CHUNK_SCALLER * 0.1) + (TERRAIN_CHUNK_SCALLER * z),
-        //         0. as f64,
-        //     ]) as f32;
-        //     pos[1] += xi * TERRAIN_HEIGHT * 0.1 / TERRAIN_XZ_TO_Y_SCALLER;
-        // }
-
-        // third pass
-        // for pos in positions.iter_mut() {
-        //     let xi: f32 = noise.get([
-        //         pos[0] as f64 / (TERRAIN_CHUNK_SCALLER * 0.01) + (TERRAIN_CHUNK_SCALLER * x),
-        //         pos[2] as f64 / (TERRAIN_CHUNK_SCALLER * 0.01) + (TERRAIN_CHUNK_SCALLER * z),
-        //         0. as f64,
-        //     ]) as f32;
-        //     pos[1] += xi * TERRAIN_HEIGHT * 0.1 / TERRAIN_XZ_TO_Y_SCALLER * 0.2;
-        // }
-
-        for pos in positions.iter_mut() {
-            pos[1] *= 2.0;
-        }
-
-        let colors: Vec<[f32; 4]> = positions
-            .iter()
-            .map(|[_, g, _]| {
-                // return Color::from(GREEN_400).to_linear().to_f32_array();
-                // return Color::BLACK.to_linear().to_f32_array();
-
-                let g = *g / TERRAIN_HEIGHT * 2.;
-                // if g > 0.8 {
-                if g > 1.1 {
-                    Color::from(GRAY_100).to_linear().to_f32_array()
-                } else if g > 1.0 {
-                    Color::from(GRAY_300).to_linear().to_f32_array()
-                } else if g > 0.9 {
-                    Color::from(AMBER_800).to_linear().to_f32_array()
-                } else if g > 0.8 {
-                    Color::from(YELLOW_400).to_linear().to_f32_array()
-                } else if g > 0.7 {
-                    Color::from(YELLOW_500).to_linear().to_f32_array()
-                } else if g > 0.6 {
-                    Color::from(AMBER_400).to_linear().to_f32_array()
-                } else if g > 0.5 {
-                    Color::from(AMBER_500).to_linear().to_f32_array()
-                } else if g > 0.4 {
-                    Color::from(AMBER_600).to_linear().to_f32_array()
-                } else if g > 0.3 {
-                    Color::from(AMBER_700).to_linear().to_f32_array()
-                } else if g > 0.2 {
-                    Color::from(AMBER_800).to_linear().to_f32_array()
-                } else if g < -0.2 {
-                    Color::from(GREEN_800).to_linear().to_f32_array()
-                } else if g < -0.5 {
-                    Color::from(ORANGE_400).to_linear().to_f32_array()
-                } else if g < -0.6 {
-                    Color::from(BLUE_400).to_linear().to_f32_array()
-                } else if g < -0.7 {
-                    Color::from(WHITE).to_linear().to_f32_array()
-                } else if g < -0.8 {
-                    Color::from(PURPLE_400).to_linear().to_f32_array()
-                } else {
-                    Color::from(GREEN_600).to_linear().to_f32_array()
-                    // Color::from(RED_600).to_linear().to_f32_array()
-                }
-            })
-            .collect();
-        terrain.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
-        terrain.compute_normals();
+        for (pos, sample) in positions.iter_mut().zip(&samples) {
+            let world = region.world_position_at(*sample);
+            *pos = [
+                world.x as f32,
+                region.heights.bilinear(*sample),
+                world.y as f32,
+            ];
+        }
+
         // terrain.translate_by();
     }
 
-    return terrain;
+    // skirts copy the normals of the edge above them, so they go on after
+    // smooth normals but before flat shading splits the vertices
+    let skirts = |terrain: &mut Mesh| match settings.lod.seams {
+        SeamFix::Skirts { depth } => seams::add_skirts(terrain, vertices, depth),
+        SeamFix::None | SeamFix::Stitch => SeamCost::default(),
+    };
+    let normals_started = Instant::now();
+    let mut seam = match (settings.shading, settings.normals) {
+        (ShadingMode::Flat, _) => {
+            let seam = skirts(&mut terrain);
+            normals::flat_shade(&mut terrain);
+            seam
+        }
+        (ShadingMode::Smooth, NormalMode::Mesh) => {
+            terrain.compute_normals();
+            skirts(&mut terrain)
+        }
+        (ShadingMode::Smooth, NormalMode::Heightfield) => {
+            terrain.insert_attribute(
+                Mesh::ATTRIBUTE_NORMAL,
+                normals::heightfield_normals(region, &samples),
+            );
+            skirts(&mut terrain)
+        }
+        (ShadingMode::Smooth, NormalMode::Analytic) => {
+            terrain.insert_attribute(
+                Mesh::ATTRIBUTE_NORMAL,
+                normals::analytic_normals(region, surface, &samples),
+            );
+            skirts(&mut terrain)
+        }
+    };
+    *normals_time += normals_started.elapsed();
+    if settings.lod.seams == SeamFix::Stitch {
+        seam.triangles = seams::collapsed_triangles(&terrain);
+    }
+
+    color_chunk(&mut terrain, settings, coloring);
+    (terrain, seam)
+}
+
+/// Sets a chunk mesh's vertex colours from its positions and normals, so
+/// the normals must already be in place: per vertex, or one per triangle
+/// from its centre when flat shaded.
+fn color_chunk(terrain: &mut Mesh, settings: &TerrainSettings, coloring: &TerrainColoring) {
+    let (
+        Some(VertexAttributeValues::Float32x3(positions)),
+        Some(VertexAttributeValues::Float32x3(normals)),
+    ) = (
+        terrain.attribute(Mesh::ATTRIBUTE_POSITION),
+        terrain.attribute(Mesh::ATTRIBUTE_NORMAL),
+    )
+    else {
+        return;
+    };
+
+    let color = |position: Vec3, normal: &[f32; 3]| {
+        coloring.color(position, Vec3::from_array(*normal), settings.height_scale)
+    };
+    let colors: Vec<[f32; 4]> = match settings.shading {
+        ShadingMode::Smooth => positions
+            .iter()
+            .zip(normals)
+            .map(|(position, normal)| color(Vec3::from_array(*position), normal))
+            .collect(),
+        // all three vertices share the face normal
+        ShadingMode::Flat => positions
+            .chunks_exact(3)
+            .zip(normals.chunks_exact(3))
+            .flat_map(|(triangle, normals)| {
+                let centre = triangle.iter().copied().map(Vec3::from_array).sum::<Vec3>() / 3.0;
+                [color(centre, &normals[0]); 3]
+            })
+            .collect(),
+    };
+    terrain.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
 }
 
 // #[derive(Component)]
@@ -258,14 +455,14 @@ fn generate_chunk(
 
 fn startup(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
     // mut images: ResMut<Assets<Image>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
+    settings: Res<TerrainSettings>,
     // mut water_materials: ResMut<Assets<ExtendedMaterial<StandardMaterial, WaterExtension>>>,
-    // asset_server: ResMut<AssetServer>,
+    asset_server: Res<AssetServer>,
 ) {
     commands.spawn((
         Camera3d::default(),
+        FlyCamera::default(),
         // OrderIndependentTransparencySettings {
         //     layer_count: 16,
         //     ..default()
@@ -353,142 +550,625 @@ fn startup(
         // ..default()
     ));
 
-    // let mut terrains: Vec<Mesh> = vec![];
+    commands.insert_resource(TerrainPaletteHandles::load(&settings, &asset_server));
 
-    for x in -2..=2 {
-        for z in -2..=2 {
-            let terrain: Mesh = generate_chunk(x as f64, z as f64);
+    let settings_asset = TerrainSettings::asset_path();
+    match &settings_asset {
+        Some(path) => info!("watching {path:?} for terrain settings changes"),
+        None => info!("terrain settings are outside the asset folder, hot reload is off"),
+    }
+    commands.insert_resource(TerrainSettingsHandle(
+        settings_asset.map(|path| asset_server.load(path)),
+    ));
+}
 
-            commands.spawn((
-                Mesh3d(meshes.add(terrain)),
-                MeshMaterial3d(materials.add(StandardMaterial {
-                    base_color: Color::WHITE,
-                    perceptual_roughness: 0.9,
-                    ..default()
-                })),
-                // Transform::from_translation(Vec3::new(-200., 0., 0.)),
-                Terrain,
-            ));
+/// Applies edits to the settings file and palette: new settings regenerate
+/// the terrain, a new palette only re-colours it.
+#[allow(clippy::too_many_arguments)]
+fn reload_terrain(
+    mut settings_events: EventReader<AssetEvent<TerrainSettings>>,
+    mut palette_events: EventReader<AssetEvent<TerrainPalette>>,
+    settings_assets: Res<Assets<TerrainSettings>>,
+    settings_handle: Res<TerrainSettingsHandle>,
+    mut settings: ResMut<TerrainSettings>,
+    mut palettes: ResMut<TerrainPaletteHandles>,
+    asset_server: Res<AssetServer>,
+    mut rebuild: ResMut<TerrainRebuild>,
+) {
+    for event in settings_events.read() {
+        let AssetEvent::Modified { id } = event else {
+            continue;
+        };
+        if settings_handle.0.as_ref().map(Handle::id) != Some(*id) {
+            continue;
+        }
+        let Some(modified) = settings_assets.get(*id) else {
+            continue;
+        };
 
-            // commands.spawn((
-            //     PbrBundle {
-            //         mesh: meshes.add(terrain),
-            //         material: materials.add(StandardMaterial {
-            //             base_color: Color::WHITE,
-            //             perceptual_roughness: 0.9,
-            //             ..default()
-            //         }),
-            //         ..default()
-            //     },
-            //     Terrain,
-            // ));
-
-            // water
-            let water = Mesh::from(
-                Plane3d::default()
-                    .mesh()
-                    .size(TERRAIN_CHUNK_W, TERRAIN_CHUNK_H)
-                    .subdivisions(TERRAIN_CHUNK_SUBDIVISIONS),
-            );
+        let mut modified = modified.clone();
+        modified.apply_env();
+        *palettes = TerrainPaletteHandles::load(&modified, &asset_server);
+        *settings = modified;
+        rebuild.mesh = true;
+        info!("terrain settings changed, regenerating");
+    }
+
+    for event in palette_events.read() {
+        if let AssetEvent::Modified { id } = event {
+            if palettes.contains(*id) {
+                rebuild.colors = true;
+                info!("terrain palette changed, re-colouring");
+            }
+        }
+    }
+}
+
+fn recolor_terrain(
+    mut meshes: ResMut<Assets<Mesh>>,
+    settings: Res<TerrainSettings>,
+    palettes: TerrainPalettes,
+    terrain: Query<&Mesh3d, With<Terrain>>,
+    mut rebuild: ResMut<TerrainRebuild>,
+    chunks: Res<TerrainChunks>,
+    mut tasks: ResMut<ChunkTasks>,
+) {
+    // a regeneration colours the new meshes anyway
+    if !rebuild.colors || rebuild.mesh {
+        return;
+    }
+    if settings.displaces_on_gpu() {
+        // the colours are baked into each chunk's textures, so bake them
+        // again from the kept heights
+        rebuild.colors = false;
+        for (chunk, loaded) in &chunks.0 {
+            tasks.queue(*chunk, loaded.source.recolored());
+        }
+        return;
+    }
+    let Some(coloring) = palettes.coloring(&settings) else {
+        return;
+    };
+    rebuild.colors = false;
+
+    for mesh in &terrain {
+        if let Some(mesh) = meshes.get_mut(&mesh.0) {
+            color_chunk(mesh, &settings, &coloring);
+        }
+    }
+}
 
-            commands.spawn((
-                Mesh3d(meshes.add(water)),
-                MeshMaterial3d(materials.add(Color::srgb(255.0, 0.0, 0.0))),
-                Transform::from_xyz(
-                    (TERRAIN_CHUNK_W * x as f32) as f32,
-                    -(TERRAIN_HEIGHT / 2.) + TERRAIN_HEIGHT * 6. / 16.,
-                    (TERRAIN_CHUNK_W * z as f32) as f32,
+/// Spawns the terrain mesh and water plane for a generated chunk.
+fn spawn_chunk(
+    commands: &mut Commands,
+    assets: &mut ChunkAssets,
+    settings: &TerrainSettings,
+    generated: GeneratedChunk,
+) -> LoadedChunk {
+    let chunk = generated.chunk;
+    let mut terrain = commands.spawn((
+        // Transform::from_translation(Vec3::new(-200., 0., 0.)),
+        Terrain,
+    ));
+    if let ChunkMesh::Cpu(_) = generated.mesh {
+        terrain.insert(MeshMaterial3d(assets.materials.add(StandardMaterial {
+            base_color: Color::WHITE,
+            perceptual_roughness: 0.9,
+            ..default()
+        })));
+    }
+    let stats = insert_chunk_mesh(
+        &mut terrain,
+        assets,
+        settings,
+        chunk,
+        generated.detail.lod,
+        generated.mesh,
+    );
+    let terrain = terrain.id();
+
+    // commands.spawn((
+    //     PbrBundle {
+    //         mesh: meshes.add(terrain),
+    //         material: materials.add(StandardMaterial {
+    //             base_color: Color::WHITE,
+    //             perceptual_roughness: 0.9,
+    //             ..default()
+    //         }),
+    //         ..default()
+    //     },
+    //     Terrain,
+    // ));
+
+    // water
+    let water = spawn_water(
+        commands,
+        &mut assets.meshes,
+        &mut assets.materials,
+        settings,
+        Vec2::new(
+            TERRAIN_CHUNK_W * chunk.x as f32,
+            TERRAIN_CHUNK_W * chunk.y as f32,
+        ),
+        Vec2::new(TERRAIN_CHUNK_W, TERRAIN_CHUNK_H),
+    );
+
+    // commands.spawn((
+    //     // Mesh3d(meshes.add(Circle::new(100.0))),
+    //     // MeshMaterial3d(materials.add(Color::srgb(7.5, 0.0, 7.5))),
+    //     // Transform::from_translation(Vec3::new(-200., 0., 0.)),
+    //     Mesh3d(meshes.add(water)),
+    //     MeshMaterial3d(water_materials.add(ExtendedMaterial {
+    //         base: StandardMaterial {
+    //             clearcoat: 0.5,
+    //             clearcoat_perceptual_roughness: 0.3,
+    //             // clearcoat_normal_texture: Some(asset_server.load_with_settings(
+    //             //     "textures/ScratchedGold-Normal.png",
+    //             //     |settings: &mut ImageLoaderSettings| settings.is_srgb = false,
+    //             // )),
+    //             metallic: 0.4,
+    //             base_color: BLUE_400.into(),
+    //             perceptual_roughness: 0.8,
+    //             // ** clearcoat: 1.0,
+    //             // ** clearcoat_perceptual_roughness: 0.3,
+    //             // ** // clearcoat_normal_texture: Some(asset_server.load_with_settings(
+    //             // ** //     "textures/ScratchedGold-Normal.png",
+    //             // ** //     |settings: &mut ImageLoaderSettings| settings.is_srgb = false,
+    //             // ** // )),
+    //             // ** metallic: 0.9,
+    //             // ** base_color: BLUE_400.into(),
+    //             // ** perceptual_roughness: 0.2,
+
+    //             // can be used in forward or deferred mode.
+    //             opaque_render_method: OpaqueRendererMethod::Auto,
+    //             // in deferred mode, only the PbrInput can be modified (uvs, color and other material properties),
+    //             // in forward mode, the output can also be modified after lighting is applied.
+    //             // see the fragment shader `extended_material.wgsl` for more info.
+    //             // Note: to run in deferred mode, you must also add a `DeferredPrepass` component to the camera and either
+    //             // change the above to `OpaqueRendererMethod::Deferred` or add the `DefaultOpaqueRendererMethod` resource.
+    //             alpha_mode: AlphaMode::Blend,
+    //             ..default()
+    //         },
+    //         extension: WaterExtension { quantize_steps: 30 },
+    //     })),
+    //     Transform::from_xyz(
+    //         (TERRAIN_CHUNK_W * chunk.x as f32) as f32,
+    //         -(TERRAIN_HEIGHT / 2.) + TERRAIN_HEIGHT * 6. / 16.,
+    //         (TERRAIN_CHUNK_W * chunk.y as f32) as f32,
+    //     ),
+    // ));
+
+    // commands.spawn(MaterialMeshBundle {
+    //     mesh: meshes.add(water),
+    //     transform: Transform::from_xyz(
+    //         (TERRAIN_CHUNK_W * chunk.x as f32) as f32,
+    //         -(TERRAIN_HEIGHT / 2.) + TERRAIN_HEIGHT * 6. / 16.,
+    //         (TERRAIN_CHUNK_W * chunk.y as f32) as f32,
+    //     ),
+    //     material: water_materials.add(ExtendedMaterial {
+    //         base: StandardMaterial {
+    //             clearcoat: 0.5,
+    //             clearcoat_perceptual_roughness: 0.3,
+    //             // clearcoat_normal_texture: Some(asset_server.load_with_settings(
+    //             //     "textures/ScratchedGold-Normal.png",
+    //             //     |settings: &mut ImageLoaderSettings| settings.is_srgb = false,
+    //             // )),
+    //             metallic: 0.4,
+    //             base_color: BLUE_400.into(),
+    //             perceptual_roughness: 0.8,
+    //             // ** clearcoat: 1.0,
+    //             // ** clearcoat_perceptual_roughness: 0.3,
+    //             // ** // clearcoat_normal_texture: Some(asset_server.load_with_settings(
+    //             // ** //     "textures/ScratchedGold-Normal.png",
+    //             // ** //     |settings: &mut ImageLoaderSettings| settings.is_srgb = false,
+    //             // ** // )),
+    //             // ** metallic: 0.9,
+    //             // ** base_color: BLUE_400.into(),
+    //             // ** perceptual_roughness: 0.2,
+
+    //             // can be used in forward or deferred mode.
+    //             opaque_render_method: OpaqueRendererMethod::Auto,
+    //             // in deferred mode, only the PbrInput can be modified (uvs, color and other material properties),
+    //             // in forward mode, the output can also be modified after lighting is applied.
+    //             // see the fragment shader `extended_material.wgsl` for more info.
+    //             // Note: to run in deferred mode, you must also add a `DeferredPrepass` component to the camera and either
+    //             // change the above to `OpaqueRendererMethod::Deferred` or add the `DefaultOpaqueRendererMethod` resource.
+    //             alpha_mode: AlphaMode::Blend,
+    //             ..default()
+    //         },
+    //         extension: WaterExtension { quantize_steps: 30 },
+    //     }),
+    //     ..default()
+    // });
+
+    LoadedChunk {
+        terrain,
+        water,
+        detail: generated.detail,
+        stats,
+        seam: generated.seam,
+        source: generated.source,
+    }
+}
+
+/// Gives a chunk's terrain entity its generated geometry. CPU meshes keep
+/// the entity's material; GPU-displaced chunks get a new one holding their
+/// textures, on the shared grid for their LOD level.
+fn insert_chunk_mesh(
+    terrain: &mut EntityCommands,
+    assets: &mut ChunkAssets,
+    settings: &TerrainSettings,
+    chunk: IVec2,
+    lod: usize,
+    mesh: ChunkMesh,
+) -> MeshStats {
+    match mesh {
+        ChunkMesh::Cpu(mesh) => {
+            let stats = MeshStats {
+                vertices: mesh.count_vertices(),
+                triangles: triangle_count(&mesh),
+                bytes: mesh_bytes(&mesh),
+            };
+            terrain.insert(Mesh3d(assets.meshes.add(mesh)));
+            stats
+        }
+        ChunkMesh::Gpu(maps) => {
+            let grid = assets
+                .grids
+                .get(&mut assets.meshes, settings.lod.subdivisions(lod));
+            let stats = MeshStats {
+                vertices: assets.meshes.get(&grid).map_or(0, Mesh::count_vertices),
+                triangles: assets.meshes.get(&grid).map_or(0, triangle_count),
+                bytes: maps.bytes(),
+            };
+            terrain.insert((
+                Mesh3d(grid),
+                maps.aabb(),
+                MeshMaterial3d(
+                    assets
+                        .terrain_materials
+                        .add(maps.material(&mut assets.images)),
                 ),
+                Transform::from_translation(streaming::chunk_centre(chunk)),
+                NotShadowCaster,
             ));
+            stats
+        }
+    }
+}
+
+/// Spawns a water plane of `size` centred on world `(x, z)` `centre`.
+fn spawn_water(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    settings: &TerrainSettings,
+    centre: Vec2,
+    size: Vec2,
+) -> Entity {
+    let water = Mesh::from(
+        Plane3d::default()
+            .mesh()
+            .size(size.x, size.y)
+            .subdivisions(TERRAIN_CHUNK_SUBDIVISIONS),
+    );
+
+    commands
+        .spawn((
+            Mesh3d(meshes.add(water)),
+            MeshMaterial3d(materials.add(Color::srgb(255.0, 0.0, 0.0))),
+            Transform::from_xyz(
+                centre.x,
+                -(settings.height_scale / 2.) + settings.height_scale * 6. / 16.,
+                centre.y,
+            ),
+            Water,
+        ))
+        .id()
+}
+
+fn triangle_count(mesh: &Mesh) -> usize {
+    mesh.indices()
+        .map_or(mesh.count_vertices(), |indices| indices.len())
+        / 3
+}
+
+/// (Re)generates and spawns the terrain whenever it's flagged for a rebuild,
+/// once the palettes have loaded (or failed to, in which case the built-in
+/// default is used).
+#[allow(clippy::too_many_arguments)]
+fn spawn_terrain(
+    mut commands: Commands,
+    settings: Res<TerrainSettings>,
+    palettes: TerrainPalettes,
+    mut rebuild: ResMut<TerrainRebuild>,
+    mut chunks: ResMut<TerrainChunks>,
+    mut tasks: ResMut<ChunkTasks>,
+    mut quadtree: ResMut<QuadtreeTerrain>,
+    mut clipmap: NonSendMut<ClipmapTerrain>,
+    mut query: ResMut<QuerySurface>,
+) {
+    if !rebuild.mesh {
+        return;
+    }
+    let Some(coloring) = palettes.coloring(&settings) else {
+        return;
+    };
+    *rebuild = TerrainRebuild {
+        mesh: false,
+        colors: false,
+    };
 
-            // commands.spawn((
-            //     // Mesh3d(meshes.add(Circle::new(100.0))),
-            //     // MeshMaterial3d(materials.add(Color::srgb(7.5, 0.0, 7.5))),
-            //     // Transform::from_translation(Vec3::new(-200., 0., 0.)),
-            //     Mesh3d(meshes.add(water)),
-            //     MeshMaterial3d(water_materials.add(ExtendedMaterial {
-            //         base: StandardMaterial {
-            //             clearcoat: 0.5,
-            //             clearcoat_perceptual_roughness: 0.3,
-            //             // clearcoat_normal_texture: Some(asset_server.load_with_settings(
-            //             //     "textures/ScratchedGold-Normal.png",
-            //             //     |settings: &mut ImageLoaderSettings| settings.is_srgb = false,
-            //             // )),
-            //             metallic: 0.4,
-            //             base_color: BLUE_400.into(),
-            //             perceptual_roughness: 0.8,
-            //             // ** clearcoat: 1.0,
-            //             // ** clearcoat_perceptual_roughness: 0.3,
-            //             // ** // clearcoat_normal_texture: Some(asset_server.load_with_settings(
-            //             // ** //     "textures/ScratchedGold-Normal.png",
-            //             // ** //     |settings: &mut ImageLoaderSettings| settings.is_srgb = false,
-            //             // ** // )),
-            //             // ** metallic: 0.9,
-            //             // ** base_color: BLUE_400.into(),
-            //             // ** perceptual_roughness: 0.2,
-
-            //             // can be used in forward or deferred mode.
-            //             opaque_render_method: OpaqueRendererMethod::Auto,
-            //             // in deferred mode, only the PbrInput can be modified (uvs, color and other material properties),
-            //             // in forward mode, the output can also be modified after lighting is applied.
-            //             // see the fragment shader `extended_material.wgsl` for more info.
-            //             // Note: to run in deferred mode, you must also add a `DeferredPrepass` component to the camera and either
-            //             // change the above to `OpaqueRendererMethod::Deferred` or add the `DefaultOpaqueRendererMethod` resource.
-            //             alpha_mode: AlphaMode::Blend,
-            //             ..default()
-            //         },
-            //         extension: WaterExtension { quantize_steps: 30 },
-            //     })),
-            //     Transform::from_xyz(
-            //         (TERRAIN_CHUNK_W * x as f32) as f32,
-            //         -(TERRAIN_HEIGHT / 2.) + TERRAIN_HEIGHT * 6. / 16.,
-            //         (TERRAIN_CHUNK_W * z as f32) as f32,
-            //     ),
-            // ));
-
-            // commands.spawn(MaterialMeshBundle {
-            //     mesh: meshes.add(water),
-            //     transform: Transform::from_xyz(
-            //         (TERRAIN_CHUNK_W * x as f32) as f32,
-            //         -(TERRAIN_HEIGHT / 2.) + TERRAIN_HEIGHT * 6. / 16.,
-            //         (TERRAIN_CHUNK_W * z as f32) as f32,
-            //     ),
-            //     material: water_materials.add(ExtendedMaterial {
-            //         base: StandardMaterial {
-            //             clearcoat: 0.5,
-            //             clearcoat_perceptual_roughness: 0.3,
-            //             // clearcoat_normal_texture: Some(asset_server.load_with_settings(
-            //             //     "textures/ScratchedGold-Normal.png",
-            //             //     |settings: &mut ImageLoaderSettings| settings.is_srgb = false,
-            //             // )),
-            //             metallic: 0.4,
-            //             base_color: BLUE_400.into(),
-            //             perceptual_roughness: 0.8,
-            //             // ** clearcoat: 1.0,
-            //             // ** clearcoat_perceptual_roughness: 0.3,
-            //             // ** // clearcoat_normal_texture: Some(asset_server.load_with_settings(
-            //             // ** //     "textures/ScratchedGold-Normal.png",
-            //             // ** //     |settings: &mut ImageLoaderSettings| settings.is_srgb = false,
-            //             // ** // )),
-            //             // ** metallic: 0.9,
-            //             // ** base_color: BLUE_400.into(),
-            //             // ** perceptual_roughness: 0.2,
-
-            //             // can be used in forward or deferred mode.
-            //             opaque_render_method: OpaqueRendererMethod::Auto,
-            //             // in deferred mode, only the PbrInput can be modified (uvs, color and other material properties),
-            //             // in forward mode, the output can also be modified after lighting is applied.
-            //             // see the fragment shader `extended_material.wgsl` for more info.
-            //             // Note: to run in deferred mode, you must also add a `DeferredPrepass` component to the camera and either
-            //             // change the above to `OpaqueRendererMethod::Deferred` or add the `DefaultOpaqueRendererMethod` resource.
-            //             alpha_mode: AlphaMode::Blend,
-            //             ..default()
-            //         },
-            //         extension: WaterExtension { quantize_steps: 30 },
-            //     }),
-            //     ..default()
-            // });
+    for (_, entities) in chunks.0.drain() {
+        entities.despawn(&mut commands);
+    }
+    tasks.clear();
+    quadtree.clear(&mut commands);
+    clipmap.clear(&mut commands);
+    query.clear();
+    if settings.mode == TerrainMode::Clipmap {
+        // `update_clipmap` samples the rings straight from the surface,
+        // once the heightmap (if any) has loaded
+        tasks.start_heightmap(&settings);
+        return;
+    }
+    if settings.streams() {
+        // `stream_chunks` fills in around the camera from here; streaming
+        // is procedural only, so there's no heightmap to wait for
+        if settings.erosion.iterations > 0 || settings.thermal.iterations > 0 {
+            warn!("streamed chunks aren't eroded; turn streaming off to see erosion");
         }
+        query.set(&settings, None);
+        return;
+    }
+    tasks.start_grid(&settings, Arc::new(coloring));
+}
+
+/// Starts queued chunk tasks while there are free slots.
+fn start_chunk_tasks(
+    settings: Res<TerrainSettings>,
+    palettes: TerrainPalettes,
+    mut tasks: ResMut<ChunkTasks>,
+    chunks: Res<TerrainChunks>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+) {
+    let limit = settings.chunk_task_limit();
+    if tasks.queued() == 0 || tasks.running() >= limit {
+        return;
+    }
+    let Some(coloring) = palettes.coloring(&settings) else {
+        return;
+    };
+    let camera = active_camera(&cameras);
+    let level = |chunk: IVec2| {
+        let distance = camera.map_or(0.0, |camera| {
+            camera.distance(streaming::chunk_centre(chunk))
+        });
+        settings.lod.level(
+            distance,
+            chunks.0.get(&chunk).map(|loaded| loaded.detail.lod),
+        )
+    };
+    tasks.start(limit, &settings, &Arc::new(coloring), |chunk| {
+        ChunkDetail {
+            lod: level(chunk),
+            // loaded neighbours keep their mesh's level until they're
+            // re-meshed themselves
+            neighbours: NEIGHBOURS.map(|offset| {
+                let neighbour = chunk + offset;
+                chunks
+                    .0
+                    .get(&neighbour)
+                    .map_or_else(|| level(neighbour), |loaded| loaded.detail.lod)
+            }),
+        }
+    });
+}
+
+/// Queues a new mesh for chunks whose LOD level has changed, or whose
+/// stitched edges no longer match their neighbours, and records the
+/// triangles in view per level and the geometry spent on seams.
+fn update_chunk_lod(
+    settings: Res<TerrainSettings>,
+    mut diagnostics: Diagnostics,
+    chunks: Res<TerrainChunks>,
+    mut tasks: ResMut<ChunkTasks>,
+    grids: Res<SharedGrids>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    visibility: Query<&ViewVisibility, With<Terrain>>,
+) {
+    if settings.mode != TerrainMode::Chunks {
+        return;
+    }
+    let Some(camera) = active_camera(&cameras) else {
+        return;
+    };
+
+    let mut triangles = vec![0; LOD_TRIANGLES.len()];
+    let mut seam = SeamCost::default();
+    let mut bytes = if settings.displaces_on_gpu() {
+        grids.bytes
+    } else {
+        0
+    };
+    for (chunk, loaded) in &chunks.0 {
+        let distance = camera.distance(streaming::chunk_centre(*chunk));
+        let lod = loaded.detail.lod;
+        // the subdivisions a stitched edge was snapped to
+        let edge = |neighbour: usize| {
+            settings
+                .lod
+                .subdivisions(neighbour)
+                .min(settings.lod.subdivisions(lod))
+        };
+        let stale_seams = settings.lod.seams == SeamFix::Stitch
+            && NEIGHBOURS
+                .iter()
+                .zip(loaded.detail.neighbours)
+                .any(|(offset, meshed)| {
+                    chunks
+                        .0
+                        .get(&(*chunk + *offset))
+                        .is_some_and(|neighbour| edge(neighbour.detail.lod) != edge(meshed))
+                });
+        if stale_seams || settings.lod.level(distance, Some(lod)) != lod {
+            tasks.queue(*chunk, loaded.source.clone());
+        }
+        bytes += loaded.stats.bytes;
+        seam.vertices += loaded.seam.vertices;
+        seam.triangles += loaded.seam.triangles;
+
+        if visibility
+            .get(loaded.terrain)
+            .is_ok_and(|visibility| visibility.get())
+        {
+            triangles[loaded.detail.lod.min(LOD_TRIANGLES.len() - 1)] += loaded.stats.triangles;
+        }
+    }
+
+    for (path, triangles) in LOD_TRIANGLES.iter().zip(triangles) {
+        diagnostics.add_measurement(path, || triangles as f64);
+    }
+    diagnostics.add_measurement(&SEAM_VERTICES, || seam.vertices as f64);
+    diagnostics.add_measurement(&SEAM_TRIANGLES, || seam.triangles as f64);
+    diagnostics.add_measurement(&TERRAIN_MESH_BYTES, || bytes as f64);
+}
+
+/// World position of the active camera, if there is one.
+fn active_camera(cameras: &Query<(&Camera, &GlobalTransform)>) -> Option<Vec3> {
+    cameras
+        .iter()
+        .find(|(camera, _)| camera.is_active)
+        .map(|(_, transform)| transform.translation())
+}
+
+/// Spawns the chunks whose tasks have finished and records their timings.
+#[allow(clippy::too_many_arguments)]
+fn finish_chunk_tasks(
+    mut commands: Commands,
+    mut assets: ChunkAssets,
+    settings: Res<TerrainSettings>,
+    mut diagnostics: Diagnostics,
+    mut tasks: ResMut<ChunkTasks>,
+    mut chunks: ResMut<TerrainChunks>,
+    mut quadtree: ResMut<QuadtreeTerrain>,
+    mut query: ResMut<QuerySurface>,
+) {
+    if let Some(grid) = tasks.finished_grid() {
+        record_stage_times(&mut diagnostics, &grid.times);
+        query.set(&settings, grid.heightmap.clone());
+        match settings.mode {
+            TerrainMode::Chunks => tasks.queue_grid(&grid),
+            // patches are meshed as the camera needs them, by
+            // `update_quadtree`
+            TerrainMode::Quadtree => {
+                if let Some(build) = tasks.build.take() {
+                    let generation_ms = build.started.elapsed().as_secs_f64() * 1000.0;
+                    info!("generated quadtree terrain region in {generation_ms:.1}ms");
+                    diagnostics.add_measurement(&TERRAIN_GENERATION_TIME, || generation_ms);
+                }
+                let tree = Quadtree::new(&settings.quadtree, grid.region);
+                let (centre, size) = tree.extent();
+                quadtree.water = Some(spawn_water(
+                    &mut commands,
+                    &mut assets.meshes,
+                    &mut assets.materials,
+                    &settings,
+                    centre,
+                    Vec2::splat(size),
+                ));
+                quadtree.tree = Some(tree);
+            }
+            // samples its own heights; no grid is started for it
+            TerrainMode::Clipmap => {}
+        }
+    }
+
+    for generated in tasks.finished() {
+        let times = generated.times;
+        let (generation, latency) = (generated.generation, generated.latency);
+
+        let chunk = generated.chunk;
+        // only first spawns count towards a grid build; re-meshes of loaded
+        // chunks can finish while it's still running
+        let (vertex_count, spawned) = match chunks.0.get_mut(&chunk) {
+            // a LOD change or re-colour: swap the mesh, dropping the old one
+            Some(loaded) => {
+                loaded.detail = generated.detail;
+                loaded.seam = generated.seam;
+                loaded.source = generated.source;
+                loaded.stats = insert_chunk_mesh(
+                    &mut commands.entity(loaded.terrain),
+                    &mut assets,
+                    &settings,
+                    chunk,
+                    generated.detail.lod,
+                    generated.mesh,
+                );
+                (loaded.stats.vertices, false)
+            }
+            None => {
+                let loaded = spawn_chunk(&mut commands, &mut assets, &settings, generated);
+                let vertices = loaded.stats.vertices;
+                chunks.0.insert(chunk, loaded);
+                (vertices, true)
+            }
+        };
+
+        record_stage_times(&mut diagnostics, &times);
+        diagnostics.add_measurement(&CHUNK_GENERATION_TIME, || generation.as_secs_f64() * 1000.0);
+        diagnostics.add_measurement(&CHUNK_LATENCY, || latency.as_secs_f64() * 1000.0);
+
+        match &mut tasks.build {
+            Some(build) if spawned && build.remaining > 0 => {
+                build.remaining -= 1;
+                build.vertices += vertex_count;
+                build.normals += times.normals;
+            }
+            _ => {
+                diagnostics.add_measurement(&NORMALS_TIME, || times.normals.as_secs_f64() * 1000.0)
+            }
+        }
+    }
+
+    if let Some(build) = tasks
+        .build
+        .take_if(|build| build.chunks > 0 && build.remaining == 0)
+    {
+        let chunk_count = build.chunks;
+        let generation_ms = build.started.elapsed().as_secs_f64() * 1000.0;
+        let layer_kinds: Vec<&str> = settings
+            .layers
+            .iter()
+            .map(|layer| layer.kind.name())
+            .collect();
+        info!("generated {chunk_count} chunks in {generation_ms:.1}ms (layers: {layer_kinds:?})");
+        diagnostics.add_measurement(&TERRAIN_GENERATION_TIME, || generation_ms);
+
+        let normals_ms = build.normals.as_secs_f64() * 1000.0;
+        let shading = match settings.shading {
+            ShadingMode::Smooth => settings.normals.name(),
+            ShadingMode::Flat => "flat",
+        };
+        info!(
+            "{shading} normals: {normals_ms:.2}ms for {chunk_count} chunks, {} vertices",
+            build.vertices
+        );
+        diagnostics.add_measurement(&NORMALS_TIME, || normals_ms);
+        diagnostics.add_measurement(&TERRAIN_VERTICES, || build.vertices as f64);
+    }
+
+    diagnostics.add_measurement(&QUEUED_CHUNKS, || tasks.queued() as f64);
+    diagnostics.add_measurement(&CHUNK_TASKS, || tasks.running() as f64);
+}
+
+/// Records the erosion passes' times, for those that ran.
+fn record_stage_times(diagnostics: &mut Diagnostics, times: &StageTimes) {
+    if let Some(erosion) = times.erosion {
+        diagnostics.add_measurement(&EROSION_TIME, || erosion.as_secs_f64() * 1000.0);
+    }
+    if let Some(weathering) = times.weathering {
+        diagnostics.add_measurement(&THERMAL_EROSION_TIME, || weathering.as_secs_f64() * 1000.0);
     }
 }
 
@@ -528,6 +1208,304 @@ fn startup(
 #[derive(Component)]
 struct Terrain;
 
+#[derive(Component)]
+struct Water;
+
+/// Everything spawned for one chunk, and what's needed to mesh it again.
+struct LoadedChunk {
+    terrain: Entity,
+    water: Entity,
+    /// LOD levels the current mesh was built for.
+    detail: ChunkDetail,
+    stats: MeshStats,
+    seam: SeamCost,
+    source: ChunkSource,
+}
+
+impl LoadedChunk {
+    /// Dropping the entities drops the last handles to their meshes and
+    /// materials, which frees them.
+    fn despawn(self, commands: &mut Commands) {
+        commands.entity(self.terrain).despawn();
+        commands.entity(self.water).despawn();
+    }
+}
+
+/// A spawned clipmap ring.
+struct ClipmapRing {
+    terrain: Entity,
+    vertices: usize,
+    triangles: usize,
+}
+
+/// The clipmap terrain, the surface its rings sample and their entities.
+/// A non-send resource, as the noise isn't `Send`.
+#[derive(Default)]
+struct ClipmapTerrain {
+    clipmap: Option<Clipmap>,
+    surface: Option<TerrainSurface>,
+    /// Finest first.
+    rings: Vec<ClipmapRing>,
+    water: Option<Entity>,
+}
+
+impl ClipmapTerrain {
+    fn clear(&mut self, commands: &mut Commands) {
+        for ring in self.rings.drain(..) {
+            commands.entity(ring.terrain).despawn();
+        }
+        if let Some(water) = self.water.take() {
+            commands.entity(water).despawn();
+        }
+        *self = Self::default();
+    }
+}
+
+/// Size of a chunk's current mesh.
+#[derive(Clone, Copy)]
+struct MeshStats {
+    vertices: usize,
+    triangles: usize,
+    /// Vertex, index and texture bytes uploaded for it alone.
+    bytes: usize,
+}
+
+/// Assets chunk meshes and materials are added to.
+#[derive(SystemParam)]
+struct ChunkAssets<'w> {
+    meshes: ResMut<'w, Assets<Mesh>>,
+    materials: ResMut<'w, Assets<StandardMaterial>>,
+    terrain_materials: ResMut<'w, Assets<TerrainMaterial>>,
+    images: ResMut<'w, Assets<Image>>,
+    grids: ResMut<'w, SharedGrids>,
+}
+
+/// Spawned chunks by chunk coordinate.
+#[derive(Resource, Default)]
+struct TerrainChunks(HashMap<IVec2, LoadedChunk>);
+
+/// A spawned quadtree patch.
+struct QuadtreeNode {
+    terrain: Entity,
+    vertices: usize,
+    triangles: usize,
+}
+
+/// The quadtree terrain, once its region has generated, and its spawned
+/// patches.
+#[derive(Resource, Default)]
+struct QuadtreeTerrain {
+    tree: Option<Quadtree>,
+    nodes: HashMap<NodeKey, QuadtreeNode>,
+    water: Option<Entity>,
+    /// One per level, shared by the patches drawn at it.
+    materials: Vec<Handle<QuadtreeMaterial>>,
+    /// Camera position the materials' morphs follow.
+    camera: Option<Vec3>,
+}
+
+impl QuadtreeTerrain {
+    fn clear(&mut self, commands: &mut Commands) {
+        for (_, node) in self.nodes.drain() {
+            commands.entity(node.terrain).despawn();
+        }
+        if let Some(water) = self.water.take() {
+            commands.entity(water).despawn();
+        }
+        *self = Self::default();
+    }
+}
+
+/// The settings' palette and one per biome, in `biomes.list` order.
+#[derive(Resource)]
+struct TerrainPaletteHandles {
+    base: Handle<TerrainPalette>,
+    biomes: Vec<Handle<TerrainPalette>>,
+}
+
+impl TerrainPaletteHandles {
+    fn load(settings: &TerrainSettings, asset_server: &AssetServer) -> Self {
+        Self {
+            base: asset_server.load(settings.palette.clone()),
+            biomes: settings
+                .biomes
+                .list
+                .iter()
+                .map(|biome| asset_server.load(biome.palette.clone()))
+                .collect(),
+        }
+    }
+
+    fn contains(&self, id: AssetId<TerrainPalette>) -> bool {
+        self.base.id() == id || self.biomes.iter().any(|handle| handle.id() == id)
+    }
+}
+
+/// Shows each chunk vertex in its dominant biome's debug colour.
+#[derive(Resource, Default)]
+struct BiomeDebug(bool);
+
+#[derive(SystemParam)]
+struct TerrainPalettes<'w> {
+    assets: Res<'w, Assets<TerrainPalette>>,
+    handles: Res<'w, TerrainPaletteHandles>,
+    asset_server: Res<'w, AssetServer>,
+    debug: Res<'w, BiomeDebug>,
+}
+
+impl TerrainPalettes<'_> {
+    /// A loaded palette, the default if it failed to load, or `None` while
+    /// it is still loading.
+    fn get(&self, handle: &Handle<TerrainPalette>) -> Option<TerrainPalette> {
+        match self.assets.get(handle) {
+            Some(palette) => Some(palette.clone()),
+            None => match self.asset_server.get_load_state(handle) {
+                Some(LoadState::Failed(err)) => {
+                    error!("failed to load terrain palette, using the default: {err}");
+                    Some(TerrainPalette::default())
+                }
+                _ => None,
+            },
+        }
+    }
+
+    /// Colouring for `settings`, once every palette it uses has loaded.
+    fn coloring(&self, settings: &TerrainSettings) -> Option<TerrainColoring> {
+        let palette = self.get(&self.handles.base)?;
+        let biomes = match BiomeMap::new(settings) {
+            Some(map) => {
+                let palettes = self
+                    .handles
+                    .biomes
+                    .iter()
+                    .map(|handle| self.get(handle))
+                    .collect::<Option<Vec<_>>>()?;
+                Some((map, palettes))
+            }
+            None => None,
+        };
+        Some(TerrainColoring {
+            palette,
+            biomes,
+            debug: self.debug.0,
+        })
+    }
+}
+
+/// The watched settings file, `None` if it's outside the asset folder.
+#[derive(Resource)]
+struct TerrainSettingsHandle(Option<Handle<TerrainSettings>>);
+
+/// What the spawned terrain needs redoing after an asset edit.
+#[derive(Resource)]
+struct TerrainRebuild {
+    mesh: bool,
+    colors: bool,
+}
+
+impl Default for TerrainRebuild {
+    fn default() -> Self {
+        // nothing is spawned yet
+        Self {
+            mesh: true,
+            colors: false,
+        }
+    }
+}
+
+/// Queues chunks around the active camera, nearest first, and unloads far
+/// ones while streaming. Each chunk is generated as its own one-chunk
+/// region without erosion, so neighbours line up.
+fn stream_chunks(
+    mut commands: Commands,
+    settings: Res<TerrainSettings>,
+    mut diagnostics: Diagnostics,
+    mut chunks: ResMut<TerrainChunks>,
+    mut tasks: ResMut<ChunkTasks>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+) {
+    if !settings.streams() {
+        return;
+    }
+    let Some(camera) = active_camera(&cameras) else {
+        return;
+    };
+    let centre = streaming::chunk_at(camera);
+
+    let unload: Vec<IVec2> = chunks
+        .0
+        .keys()
+        .copied()
+        .filter(|chunk| settings.streaming.should_unload(centre, *chunk))
+        .collect();
+    for chunk in unload {
+        if let Some(entities) = chunks.0.remove(&chunk) {
+            entities.despawn(&mut commands);
+        }
+    }
+    tasks.retain(|chunk| !settings.streaming.should_unload(centre, chunk));
+
+    tasks.queue_noise(
+        settings
+            .streaming
+            .chunks_to_load(centre, |chunk| chunks.0.contains_key(&chunk)),
+    );
+
+    diagnostics.add_measurement(&LOADED_CHUNKS, || chunks.0.len() as f64);
+}
+
+fn toggle_biome_debug(
+    input: Res<ButtonInput<KeyCode>>,
+    settings: Res<TerrainSettings>,
+    mut debug: ResMut<BiomeDebug>,
+    mut rebuild: ResMut<TerrainRebuild>,
+) {
+    if !input.just_pressed(KeyCode::KeyB) {
+        return;
+    }
+    if settings.biomes.list.is_empty() {
+        info!("no biomes configured");
+        return;
+    }
+
+    debug.0 = !debug.0;
+    rebuild.colors = true;
+    if debug.0 {
+        let count = settings.biomes.list.len();
+        for (i, biome) in settings.biomes.list.iter().enumerate() {
+            let color = Srgba::from(LinearRgba::from_f32_array(biome::debug_color(i, count)));
+            info!("biome {i} {:?}: {}", biome.name, color.to_hex());
+        }
+    }
+}
+
+/// Logs the ground under the active camera when G is pressed.
+fn log_ground(
+    input: Res<ButtonInput<KeyCode>>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    ground: TerrainHeightQuery,
+) {
+    if !input.just_pressed(KeyCode::KeyG) {
+        return;
+    }
+    let Some(camera) = active_camera(&cameras) else {
+        return;
+    };
+    let Some(sample) = ground.sample(camera.x, camera.z) else {
+        info!("terrain not ready to query yet");
+        return;
+    };
+    let color = Srgba::from(LinearRgba::from_f32_array(sample.color));
+    info!(
+        "ground at ({:.1}, {:.1}): height {:.2}, normal {:.3}, colour {}",
+        camera.x,
+        camera.z,
+        sample.height,
+        sample.normal,
+        color.to_hex()
+    );
+}
+
 fn toggle_wireframe(
     mut commands: Commands,
     landscapes_wireframes: Query<Entity, (With<Terrain>, With<Wireframe>)>,
@@ -547,3 +1525,212 @@ fn toggle_wireframe(
         }
     }
 }
+
+/// Spawns the quadtree patches selected for the active camera, despawns the
+/// ones that merged or split away, and moves the camera the patches morph
+/// by in the vertex shader. Records the patches drawn, the time spent and
+/// the triangles in view per level, to compare against the chunk grid.
+#[allow(clippy::too_many_arguments)]
+fn update_quadtree(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<QuadtreeMaterial>>,
+    settings: Res<TerrainSettings>,
+    palettes: TerrainPalettes,
+    mut diagnostics: Diagnostics,
+    mut quadtree: ResMut<QuadtreeTerrain>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    visibility: Query<&ViewVisibility, With<Terrain>>,
+) {
+    let quadtree = &mut *quadtree;
+    let Some(tree) = &quadtree.tree else {
+        return;
+    };
+    let Some(camera) = active_camera(&cameras) else {
+        return;
+    };
+    let Some(coloring) = palettes.coloring(&settings) else {
+        return;
+    };
+    let started = Instant::now();
+
+    let selected = tree.select(camera);
+    quadtree.nodes.retain(|key, node| {
+        let keep = selected.contains(key);
+        if !keep {
+            commands.entity(node.terrain).despawn();
+        }
+        keep
+    });
+
+    if quadtree.materials.is_empty() {
+        quadtree.materials = (0..tree.lods())
+            .map(|lod| materials.add(tree.material(lod, camera)))
+            .collect();
+    } else if quadtree.camera != Some(camera) {
+        for handle in &quadtree.materials {
+            if let Some(material) = materials.get_mut(handle) {
+                material.extension.camera = camera.extend(0.0);
+            }
+        }
+    }
+    quadtree.camera = Some(camera);
+
+    for key in selected {
+        if quadtree.nodes.contains_key(&key) {
+            continue;
+        }
+        let mesh = tree.mesh(key, &settings, &coloring);
+        let vertices = mesh.count_vertices();
+        let triangles = triangle_count(&mesh);
+        let terrain = commands
+            .spawn((
+                Mesh3d(meshes.add(mesh)),
+                MeshMaterial3d(quadtree.materials[key.lod].clone()),
+                Terrain,
+            ))
+            .id();
+        quadtree.nodes.insert(
+            key,
+            QuadtreeNode {
+                terrain,
+                vertices,
+                triangles,
+            },
+        );
+    }
+    let update_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    let mut triangles = vec![0; LOD_TRIANGLES.len()];
+    let mut vertices = 0;
+    for (key, node) in &quadtree.nodes {
+        vertices += node.vertices;
+        if visibility
+            .get(node.terrain)
+            .is_ok_and(|visibility| visibility.get())
+        {
+            triangles[key.lod.min(LOD_TRIANGLES.len() - 1)] += node.triangles;
+        }
+    }
+    for (path, triangles) in LOD_TRIANGLES.iter().zip(triangles) {
+        diagnostics.add_measurement(path, || triangles as f64);
+    }
+    diagnostics.add_measurement(&QUADTREE_NODES, || quadtree.nodes.len() as f64);
+    diagnostics.add_measurement(&QUADTREE_TIME, || update_ms);
+    diagnostics.add_measurement(&TERRAIN_VERTICES, || vertices as f64);
+}
+
+/// Moves the clipmap rings with the active camera, sampling only the heights
+/// each ring moved onto, and re-meshes the rings that moved (or whose hole
+/// did). Records the heights sampled, the time spent and the triangles in
+/// view per ring.
+#[allow(clippy::too_many_arguments)]
+fn update_clipmap(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    settings: Res<TerrainSettings>,
+    palettes: TerrainPalettes,
+    mut diagnostics: Diagnostics,
+    mut terrain: NonSendMut<ClipmapTerrain>,
+    mut tasks: ResMut<ChunkTasks>,
+    mut query: ResMut<QuerySurface>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    visibility: Query<&ViewVisibility, With<Terrain>>,
+) {
+    let terrain = &mut *terrain;
+    if let Some(heightmap) = tasks.finished_heightmap() {
+        let (min_chunk, _) = settings.chunk_range(heightmap.as_deref());
+        query.set(&settings, heightmap.clone());
+        terrain.surface = Some(TerrainSurface::new(&settings, heightmap, min_chunk));
+        terrain.clipmap = Some(Clipmap::new(&settings.clipmap));
+    }
+    let (Some(clipmap), Some(surface)) = (&mut terrain.clipmap, &terrain.surface) else {
+        return;
+    };
+    let Some(camera) = active_camera(&cameras) else {
+        return;
+    };
+    let Some(coloring) = palettes.coloring(&settings) else {
+        return;
+    };
+    let started = Instant::now();
+
+    let sampled = clipmap.update(camera.xz(), |x, z| surface.height(x, z));
+    for (level, samples) in sampled.iter().enumerate() {
+        // a ring's hole moves with the ring inside it
+        let moved = *samples > 0 || (level > 0 && sampled[level - 1] > 0);
+        if level < terrain.rings.len() && !moved {
+            continue;
+        }
+        let mesh = clipmap.mesh(level, &settings, &coloring);
+        let (vertices, triangles) = (mesh.count_vertices(), triangle_count(&mesh));
+        match terrain.rings.get_mut(level) {
+            Some(ring) => {
+                commands
+                    .entity(ring.terrain)
+                    .insert(Mesh3d(meshes.add(mesh)));
+                ring.vertices = vertices;
+                ring.triangles = triangles;
+            }
+            None => {
+                let terrain_entity = commands
+                    .spawn((
+                        Mesh3d(meshes.add(mesh)),
+                        MeshMaterial3d(materials.add(StandardMaterial {
+                            base_color: Color::WHITE,
+                            perceptual_roughness: 0.9,
+                            ..default()
+                        })),
+                        // the bounds would go stale as the ring moves, and
+                        // it's always around the camera anyway
+                        NoFrustumCulling,
+                        Terrain,
+                    ))
+                    .id();
+                terrain.rings.push(ClipmapRing {
+                    terrain: terrain_entity,
+                    vertices,
+                    triangles,
+                });
+            }
+        }
+    }
+
+    // the water plane covers the outermost ring, and moves with it
+    if sampled.last().is_some_and(|samples| *samples > 0) {
+        if let Some(water) = terrain.water.take() {
+            commands.entity(water).despawn();
+        }
+        if let Some((centre, size)) = clipmap.extent() {
+            terrain.water = Some(spawn_water(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                &settings,
+                centre,
+                Vec2::splat(size),
+            ));
+        }
+    }
+    let update_ms = started.elapsed().as_secs_f64() * 1000.0;
+
+    let mut triangles = vec![0; LOD_TRIANGLES.len()];
+    let mut vertices = 0;
+    for (level, ring) in terrain.rings.iter().enumerate() {
+        vertices += ring.vertices;
+        if visibility
+            .get(ring.terrain)
+            .is_ok_and(|visibility| visibility.get())
+        {
+            triangles[level.min(LOD_TRIANGLES.len() - 1)] += ring.triangles;
+        }
+    }
+    for (path, triangles) in LOD_TRIANGLES.iter().zip(triangles) {
+        diagnostics.add_measurement(path, || triangles as f64);
+    }
+    let samples: usize = sampled.iter().sum();
+    diagnostics.add_measurement(&CLIPMAP_SAMPLES, || samples as f64);
+    diagnostics.add_measurement(&CLIPMAP_TIME, || update_ms);
+    diagnostics.add_measurement(&TERRAIN_VERTICES, || vertices as f64);
+}
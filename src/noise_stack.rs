@@ -0,0 +1,429 @@
+use noise::core::worley::{distance_functions, ReturnType};
+use noise::{
+    BasicMulti, Billow, Fbm, HybridMulti, MultiFractal, NoiseFn, OpenSimplex, Perlin, RidgedMulti,
+    Worley,
+};
+use serde::Deserialize;
+
+use crate::terrain_settings::TerrainSettings;
+
+/// How a layer's value is combined with the height accumulated so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum BlendMode {
+    /// `h + v * amplitude`
+    #[default]
+    Add,
+    /// `h * (1 + v * amplitude)`, scaling existing relief up or down.
+    Multiply,
+    /// `max(h, v * amplitude)`
+    Max,
+    /// Fades `h` towards zero where the layer is low; `amplitude` is the
+    /// mask strength in `0..=1`.
+    Mask,
+}
+
+impl BlendMode {
+    pub fn apply(self, height: f32, value: f32, amplitude: f32) -> f32 {
+        match self {
+            BlendMode::Add => height + value * amplitude,
+            BlendMode::Multiply => height * (1.0 + value * amplitude),
+            BlendMode::Max => height.max(value * amplitude),
+            BlendMode::Mask => {
+                let mask = (value * 0.5 + 0.5).clamp(0.0, 1.0);
+                height * (1.0 - amplitude + mask * amplitude)
+            }
+        }
+    }
+}
+
+fn default_octaves() -> usize {
+    6
+}
+
+fn default_frequency() -> f64 {
+    1.0
+}
+
+fn default_lacunarity() -> f64 {
+    std::f64::consts::PI * 2.0 / 3.0
+}
+
+fn default_persistence() -> f64 {
+    0.5
+}
+
+fn default_attenuation() -> f64 {
+    2.0
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum WorleyReturn {
+    #[default]
+    Value,
+    Distance,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum WorleyDistance {
+    #[default]
+    Euclidean,
+    EuclideanSquared,
+    Manhattan,
+    Chebyshev,
+}
+
+/// Generator used by a layer. Fractal kinds all run on Perlin sources;
+/// omitted parameters fall back to the `noise` crate's Fbm defaults.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub enum NoiseKind {
+    /// Same `BasicMulti<Perlin>` as the base terrain, using the top-level
+    /// octaves/frequency/lacunarity/persistence.
+    #[default]
+    BasicMulti,
+    Fbm {
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default = "default_frequency")]
+        frequency: f64,
+        #[serde(default = "default_lacunarity")]
+        lacunarity: f64,
+        #[serde(default = "default_persistence")]
+        persistence: f64,
+    },
+    RidgedMulti {
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default = "default_frequency")]
+        frequency: f64,
+        #[serde(default = "default_lacunarity")]
+        lacunarity: f64,
+        #[serde(default = "default_persistence")]
+        persistence: f64,
+        #[serde(default = "default_attenuation")]
+        attenuation: f64,
+    },
+    Billow {
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default = "default_frequency")]
+        frequency: f64,
+        #[serde(default = "default_lacunarity")]
+        lacunarity: f64,
+        #[serde(default = "default_persistence")]
+        persistence: f64,
+    },
+    HybridMulti {
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        #[serde(default = "default_frequency")]
+        frequency: f64,
+        #[serde(default = "default_lacunarity")]
+        lacunarity: f64,
+        #[serde(default = "default_persistence")]
+        persistence: f64,
+    },
+    /// Cellular noise, for plateaus and cracked-mud shapes.
+    Worley {
+        #[serde(default = "default_frequency")]
+        frequency: f64,
+        #[serde(default)]
+        return_type: WorleyReturn,
+        #[serde(default)]
+        distance: WorleyDistance,
+    },
+    OpenSimplex,
+}
+
+impl NoiseKind {
+    pub fn build(&self, settings: &TerrainSettings, seed: u32) -> Box<dyn NoiseFn<f64, 3>> {
+        match *self {
+            NoiseKind::BasicMulti => Box::new(settings.build_noise_with_seed(seed)),
+            NoiseKind::Fbm {
+                octaves,
+                frequency,
+                lacunarity,
+                persistence,
+            } => Box::new(
+                Fbm::<Perlin>::new(seed)
+                    .set_octaves(octaves)
+                    .set_frequency(frequency)
+                    .set_lacunarity(lacunarity)
+                    .set_persistence(persistence),
+            ),
+            NoiseKind::RidgedMulti {
+                octaves,
+                frequency,
+                lacunarity,
+                persistence,
+                attenuation,
+            } => Box::new(
+                RidgedMulti::<Perlin>::new(seed)
+                    .set_octaves(octaves)
+                    .set_frequency(frequency)
+                    .set_lacunarity(lacunarity)
+                    .set_persistence(persistence)
+                    .set_attenuation(attenuation),
+            ),
+            NoiseKind::Billow {
+                octaves,
+                frequency,
+                lacunarity,
+                persistence,
+            } => Box::new(
+                Billow::<Perlin>::new(seed)
+                    .set_octaves(octaves)
+                    .set_frequency(frequency)
+                    .set_lacunarity(lacunarity)
+                    .set_persistence(persistence),
+            ),
+            NoiseKind::HybridMulti {
+                octaves,
+                frequency,
+                lacunarity,
+                persistence,
+            } => Box::new(
+                HybridMulti::<Perlin>::new(seed)
+                    .set_octaves(octaves)
+                    .set_frequency(frequency)
+                    .set_lacunarity(lacunarity)
+                    .set_persistence(persistence),
+            ),
+            NoiseKind::Worley {
+                frequency,
+                return_type,
+                distance,
+            } => {
+                let worley =
+                    Worley::new(seed)
+                        .set_frequency(frequency)
+                        .set_return_type(match return_type {
+                            WorleyReturn::Value => ReturnType::Value,
+                            WorleyReturn::Distance => ReturnType::Distance,
+                        });
+                Box::new(match distance {
+                    WorleyDistance::Euclidean => {
+                        worley.set_distance_function(distance_functions::euclidean)
+                    }
+                    WorleyDistance::EuclideanSquared => {
+                        worley.set_distance_function(distance_functions::euclidean_squared)
+                    }
+                    WorleyDistance::Manhattan => {
+                        worley.set_distance_function(distance_functions::manhattan)
+                    }
+                    WorleyDistance::Chebyshev => {
+                        worley.set_distance_function(distance_functions::chebyshev)
+                    }
+                })
+            }
+            NoiseKind::OpenSimplex => Box::new(OpenSimplex::new(seed)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NoiseKind::BasicMulti => "BasicMulti",
+            NoiseKind::Fbm { .. } => "Fbm",
+            NoiseKind::RidgedMulti { .. } => "RidgedMulti",
+            NoiseKind::Billow { .. } => "Billow",
+            NoiseKind::HybridMulti { .. } => "HybridMulti",
+            NoiseKind::Worley { .. } => "Worley",
+            NoiseKind::OpenSimplex => "OpenSimplex",
+        }
+    }
+}
+
+/// One detail pass on top of the base noise. `frequency` is relative to
+/// the base sampling scale (`noise_scale`), `offset` is added in
+/// noise space after scaling.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct NoiseLayer {
+    pub kind: NoiseKind,
+    pub frequency: f64,
+    pub amplitude: f32,
+    pub offset: [f64; 2],
+    pub blend: BlendMode,
+}
+
+impl Default for NoiseLayer {
+    fn default() -> Self {
+        Self {
+            kind: NoiseKind::BasicMulti,
+            frequency: 1.0,
+            amplitude: 1.0,
+            offset: [0.0, 0.0],
+            blend: BlendMode::Add,
+        }
+    }
+}
+
+/// Offsets the sample position by two secondary Fbm fields before any
+/// height noise is evaluated, bending ridgelines into flowing shapes.
+/// Works in world space, so warped terrain stays seamless across chunks.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct DomainWarp {
+    /// Offset per iteration in noise-space units; `0.0` disables warping.
+    pub strength: f64,
+    pub iterations: u32,
+    /// Warp field frequency relative to the base sampling scale.
+    pub frequency: f64,
+    pub octaves: usize,
+}
+
+impl Default for DomainWarp {
+    fn default() -> Self {
+        Self {
+            strength: 0.0,
+            iterations: 1,
+            frequency: 1.0,
+            octaves: 4,
+        }
+    }
+}
+
+/// Largest seed a noise field can take: fractal noise seeds octave `n`
+/// with `seed + n` unchecked, for up to 32 octaves.
+pub const MAX_SEED: u32 = u32::MAX - 32;
+
+/// `seed` shifted by `offset` for a secondary noise field, wrapping around
+/// within `0..=MAX_SEED` so each offset of a seed gets a field of its own.
+pub fn derive_seed(seed: u32, offset: i32) -> u32 {
+    (seed as i64 + offset as i64).rem_euclid(MAX_SEED as i64 + 1) as u32
+}
+
+struct WarpFields {
+    warp: DomainWarp,
+    x: Fbm<Perlin>,
+    z: Fbm<Perlin>,
+}
+
+impl WarpFields {
+    fn new(settings: &TerrainSettings) -> Option<Self> {
+        let warp = &settings.warp;
+        if warp.strength == 0.0 || warp.iterations == 0 {
+            return None;
+        }
+
+        let field = |seed: u32| {
+            Fbm::<Perlin>::new(seed)
+                .set_octaves(warp.octaves)
+                .set_frequency(warp.frequency)
+        };
+        Some(Self {
+            warp: warp.clone(),
+            x: field(derive_seed(settings.seed, -1)),
+            z: field(derive_seed(settings.seed, -2)),
+        })
+    }
+
+    fn apply(&self, mut x: f64, mut z: f64) -> (f64, f64) {
+        for _ in 0..self.warp.iterations {
+            let dx = self.x.get([x, z, 0.]);
+            let dz = self.z.get([x, z, 0.]);
+            x += dx * self.warp.strength;
+            z += dz * self.warp.strength;
+        }
+        (x, z)
+    }
+}
+
+/// Noise functions built once from `TerrainSettings` and shared by every
+/// vertex of a chunk.
+pub struct TerrainNoise {
+    base: BasicMulti<Perlin>,
+    layers: Vec<(NoiseLayer, Box<dyn NoiseFn<f64, 3>>)>,
+    warp: Option<WarpFields>,
+    scale: f64,
+}
+
+impl TerrainNoise {
+    pub fn new(settings: &TerrainSettings) -> Self {
+        let layers = settings
+            .layers
+            .iter()
+            .enumerate()
+            .map(|(i, layer)| {
+                // each layer gets its own seed so detail passes don't just
+                // repeat the base shape at a different scale
+                let seed = derive_seed(settings.seed, i as i32 + 1);
+                (layer.clone(), layer.kind.build(settings, seed))
+            })
+            .collect();
+
+        Self {
+            base: settings.build_noise(),
+            layers,
+            warp: WarpFields::new(settings),
+            scale: settings.noise_scale,
+        }
+    }
+
+    /// Normalised height (before `height_scale`) at world position `(x, z)`.
+    pub fn sample(&self, x: f64, z: f64) -> f32 {
+        let x = x / self.scale;
+        let z = z / self.scale;
+        let (x, z) = match &self.warp {
+            Some(warp) => warp.apply(x, z),
+            None => (x, z),
+        };
+
+        let mut height = self.base.get([x, z, 0.]) as f32;
+        for (layer, noise) in &self.layers {
+            let value = noise.get([
+                x * layer.frequency + layer.offset[0],
+                z * layer.frequency + layer.offset[1],
+                0.,
+            ]) as f32;
+            height = layer.blend.apply(height, value, layer.amplitude);
+        }
+        height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derived_seeds_differ_for_seed_zero() {
+        // the warp fields, then temperature and moisture
+        let seeds = [-1, -2, -3, -4].map(|offset| derive_seed(0, offset));
+        for (i, a) in seeds.iter().enumerate() {
+            for b in &seeds[i + 1..] {
+                assert_ne!(a, b, "{seeds:?}");
+            }
+        }
+        assert!(seeds.iter().all(|&seed| seed <= MAX_SEED));
+    }
+
+    /// Fractal noise adds the octave to the seed unchecked, which panics in
+    /// debug builds if a warp field's seed wraps below zero.
+    #[test]
+    fn warped_stack_builds_for_seed_zero() {
+        for seed in [0, MAX_SEED] {
+            let settings = TerrainSettings {
+                seed,
+                warp: DomainWarp {
+                    strength: 0.5,
+                    octaves: 32,
+                    ..DomainWarp::default()
+                },
+                ..TerrainSettings::default()
+            };
+            let noise = TerrainNoise::new(&settings);
+            let warp = noise.warp.as_ref().unwrap();
+            // the x and z fields differ, so warping isn't stuck on a diagonal
+            let point = [0.37, 1.91, 0.];
+            assert_ne!(warp.x.get(point), warp.z.get(point));
+            assert!(noise.sample(120.0, -45.0).is_finite());
+        }
+    }
+
+    #[test]
+    fn derived_seeds_stay_in_range() {
+        assert_eq!(derive_seed(MAX_SEED, 1), 0);
+        assert_eq!(derive_seed(0, -1), MAX_SEED);
+        assert_eq!(derive_seed(5, 0), 5);
+    }
+}
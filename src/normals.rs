@@ -0,0 +1,114 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::heightfield::{TerrainRegion, TerrainSurface};
+
+/// Distance in world units either side of a vertex at which `Analytic`
+/// normals sample the surface. Independent of the mesh resolution.
+const ANALYTIC_EPSILON: f64 = 0.5;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum NormalMode {
+    /// `Mesh::compute_normals` on each chunk on its own. Edge vertices only
+    /// see one side of the border, so lighting seams show between chunks.
+    Mesh,
+    /// Central differences on the shared region heightfield, reaching into
+    /// the neighbouring chunk (or the apron) at the edges. Vertices shared
+    /// by two chunks get identical normals.
+    #[default]
+    Heightfield,
+    /// Central differences of the continuous surface (noise or imported
+    /// heightmap) close to each vertex, so shading stays smooth however few
+    /// subdivisions the chunks have. Ignores erosion, which only exists in
+    /// the sampled heights.
+    Analytic,
+}
+
+impl NormalMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            NormalMode::Mesh => "mesh",
+            NormalMode::Heightfield => "heightfield",
+            NormalMode::Analytic => "analytic",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum ShadingMode {
+    /// Indexed grid with shared vertices, shaded with `NormalMode`.
+    #[default]
+    Smooth,
+    /// Every triangle gets its own three vertices, a face normal and one
+    /// colour from its average height: the faceted low-poly look, at about
+    /// three times the vertex count. `NormalMode` is ignored.
+    Flat,
+}
+
+/// Un-indexes `mesh` so no vertex is shared and gives each triangle its
+/// face normal.
+pub fn flat_shade(mesh: &mut Mesh) {
+    mesh.duplicate_vertices();
+    mesh.compute_flat_normals();
+}
+
+/// Normal at region sample `(x, z)` by central differences, one-sided at
+/// the very edge of the region (when there's no apron).
+pub fn sample_normal(region: &TerrainRegion, x: usize, z: usize) -> Vec3 {
+    let heights = &region.heights;
+    let spacing = TerrainRegion::spacing();
+
+    let left = x.saturating_sub(1);
+    let right = (x + 1).min(heights.width - 1);
+    let up = z.saturating_sub(1);
+    let down = (z + 1).min(heights.depth - 1);
+
+    let dx = (heights.get(right, z) - heights.get(left, z)) / ((right - left) as f32 * spacing.x);
+    let dz = (heights.get(x, down) - heights.get(x, up)) / ((down - up) as f32 * spacing.y);
+    Vec3::new(-dx, 1.0, -dz).normalize()
+}
+
+/// Normal at fractional sample coordinates, interpolated between the
+/// normals of the four surrounding samples.
+pub fn sample_normal_at(region: &TerrainRegion, sample: Vec2) -> Vec3 {
+    let (x0, z0, x1, z1, u, v) = region.heights.cell(sample);
+    let top = sample_normal(region, x0, z0).lerp(sample_normal(region, x1, z0), u);
+    let bottom = sample_normal(region, x0, z1).lerp(sample_normal(region, x1, z1), u);
+    top.lerp(bottom, v).normalize()
+}
+
+/// Per-vertex normals of a chunk mesh whose vertices sit at region
+/// `samples`, in the same order.
+pub fn heightfield_normals(region: &TerrainRegion, samples: &[Vec2]) -> Vec<[f32; 3]> {
+    samples
+        .iter()
+        .map(|sample| sample_normal_at(region, *sample).to_array())
+        .collect()
+}
+
+/// Per-vertex normals of a chunk mesh whose vertices sit at region
+/// `samples`, from the surface gradient at each vertex.
+pub fn analytic_normals(
+    region: &TerrainRegion,
+    surface: &TerrainSurface,
+    samples: &[Vec2],
+) -> Vec<[f32; 3]> {
+    samples
+        .iter()
+        .map(|sample| {
+            let world = region.world_position_at(*sample);
+            surface_normal(surface, world.x, world.y).to_array()
+        })
+        .collect()
+}
+
+/// Normal of the continuous surface at world `(x, z)`, by central
+/// differences close to the point.
+pub fn surface_normal(surface: &TerrainSurface, x: f64, z: f64) -> Vec3 {
+    let span = (2.0 * ANALYTIC_EPSILON) as f32;
+    let dx =
+        (surface.height(x + ANALYTIC_EPSILON, z) - surface.height(x - ANALYTIC_EPSILON, z)) / span;
+    let dz =
+        (surface.height(x, z + ANALYTIC_EPSILON) - surface.height(x, z - ANALYTIC_EPSILON)) / span;
+    Vec3::new(-dx, 1.0, -dz).normalize()
+}
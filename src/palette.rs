@@ -0,0 +1,507 @@
+use std::fmt;
+
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, LoadContext};
+use bevy::color::palettes::tailwind::*;
+use bevy::prelude::*;
+use serde::Deserialize;
+
+/// Palette loaded at startup, relative to the asset folder.
+pub const TERRAIN_PALETTE_PATH: &str = "terrain.palette.ron";
+
+/// Height range a stop applies to, in units of half `height_scale` (the
+/// noise terrain spans roughly `-1.0..=1.0`). Both bounds are exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum Threshold {
+    Above(f32),
+    Below(f32),
+}
+
+impl Threshold {
+    fn matches(&self, g: f32) -> bool {
+        match *self {
+            Threshold::Above(t) => g > t,
+            Threshold::Below(t) => g < t,
+        }
+    }
+}
+
+/// A stop as written in the palette file, colour as `#rrggbb` sRGB hex.
+#[derive(Clone, Debug, Deserialize)]
+struct StopFile {
+    when: Threshold,
+    color: String,
+}
+
+/// A slope rule as written in the palette file; a missing `height` range
+/// matches every height.
+#[derive(Clone, Debug, Deserialize)]
+struct RuleFile {
+    slope: (f32, f32),
+    #[serde(default)]
+    height: Option<(f32, f32)>,
+    color: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct PaletteFile {
+    #[serde(default)]
+    rules: Vec<RuleFile>,
+    stops: Vec<StopFile>,
+    fallback: String,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ColorStop {
+    pub when: Threshold,
+    /// Linear RGBA, ready to use as a vertex colour.
+    pub color: [f32; 4],
+}
+
+/// Colour for ground whose slope, in degrees from flat, lies within
+/// `slope` and whose height (in the same units as the stops) lies within
+/// `height`. Both ranges are inclusive.
+#[derive(Clone, Copy, Debug)]
+pub struct SlopeRule {
+    pub slope: (f32, f32),
+    pub height: (f32, f32),
+    pub color: [f32; 4],
+}
+
+impl SlopeRule {
+    fn matches(&self, g: f32, slope: f32) -> bool {
+        (self.slope.0..=self.slope.1).contains(&slope)
+            && (self.height.0..=self.height.1).contains(&g)
+    }
+}
+
+/// Slope and height to colour: the first matching slope rule wins, then
+/// the first stop whose threshold matches; anything else gets `fallback`.
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct TerrainPalette {
+    pub rules: Vec<SlopeRule>,
+    pub stops: Vec<ColorStop>,
+    pub fallback: [f32; 4],
+}
+
+impl Default for TerrainPalette {
+    /// The height bands the terrain has always had, minus the `Below` stops
+    /// that could never match behind `Below(-0.2)`, plus rock on steep faces
+    /// and snow on flat peaks. Matches `assets/terrain.palette.ron`.
+    fn default() -> Self {
+        let stop = |when, color: Srgba| ColorStop {
+            when,
+            color: LinearRgba::from(color).to_f32_array(),
+        };
+        Self {
+            rules: vec![
+                SlopeRule {
+                    slope: (45.0, 90.0),
+                    height: (f32::NEG_INFINITY, f32::INFINITY),
+                    color: LinearRgba::from(STONE_600).to_f32_array(),
+                },
+                SlopeRule {
+                    slope: (0.0, 20.0),
+                    height: (0.9, f32::INFINITY),
+                    color: LinearRgba::from(GRAY_100).to_f32_array(),
+                },
+            ],
+            stops: vec![
+                stop(Threshold::Above(1.1), GRAY_100),
+                stop(Threshold::Above(1.0), GRAY_300),
+                stop(Threshold::Above(0.9), AMBER_800),
+                stop(Threshold::Above(0.8), YELLOW_400),
+                stop(Threshold::Above(0.7), YELLOW_500),
+                stop(Threshold::Above(0.6), AMBER_400),
+                stop(Threshold::Above(0.5), AMBER_500),
+                stop(Threshold::Above(0.4), AMBER_600),
+                stop(Threshold::Above(0.3), AMBER_700),
+                stop(Threshold::Above(0.2), AMBER_800),
+                stop(Threshold::Below(-0.2), GREEN_800),
+            ],
+            fallback: LinearRgba::from(GREEN_600).to_f32_array(),
+        }
+    }
+}
+
+impl TerrainPalette {
+    /// Checks that `stops` only ever narrow the band left for the stops
+    /// after them: `Above` thresholds must fall and `Below` thresholds rise,
+    /// and an `Above` and a `Below` range may not overlap. Anything else
+    /// would leave a stop that never matches, or only partly matches.
+    /// Slope rules only need non-empty ranges, with slopes in `0..=90`.
+    pub fn new(
+        rules: Vec<SlopeRule>,
+        stops: Vec<ColorStop>,
+        fallback: [f32; 4],
+    ) -> Result<Self, PaletteError> {
+        for (i, rule) in rules.iter().enumerate() {
+            let reason = if rule.slope.0 > rule.slope.1 || rule.height.0 > rule.height.1 {
+                "range minimum is above its maximum"
+            } else if rule.slope.0 < 0.0 || rule.slope.1 > 90.0 {
+                "slope must be within 0..=90 degrees"
+            } else {
+                continue;
+            };
+            return Err(PaletteError::Rule { rule: i, reason });
+        }
+
+        // heights above `upper` / below `lower` are taken by earlier stops
+        let mut upper: Option<(usize, f32)> = None;
+        let mut lower: Option<(usize, f32)> = None;
+
+        for (i, stop) in stops.iter().enumerate() {
+            match stop.when {
+                Threshold::Above(t) => {
+                    if let Some((by, u)) = upper.filter(|(_, u)| t >= *u) {
+                        return Err(PaletteError::Unreachable {
+                            stop: i,
+                            when: stop.when,
+                            by,
+                            by_when: Threshold::Above(u),
+                        });
+                    }
+                    if let Some((with, l)) = lower.filter(|(_, l)| t < *l) {
+                        return Err(PaletteError::Overlap {
+                            stop: i,
+                            when: stop.when,
+                            with,
+                            with_when: Threshold::Below(l),
+                        });
+                    }
+                    upper = Some((i, t));
+                }
+                Threshold::Below(t) => {
+                    if let Some((by, l)) = lower.filter(|(_, l)| t <= *l) {
+                        return Err(PaletteError::Unreachable {
+                            stop: i,
+                            when: stop.when,
+                            by,
+                            by_when: Threshold::Below(l),
+                        });
+                    }
+                    if let Some((with, u)) = upper.filter(|(_, u)| t > *u) {
+                        return Err(PaletteError::Overlap {
+                            stop: i,
+                            when: stop.when,
+                            with,
+                            with_when: Threshold::Above(u),
+                        });
+                    }
+                    lower = Some((i, t));
+                }
+            }
+        }
+
+        Ok(Self {
+            rules,
+            stops,
+            fallback,
+        })
+    }
+
+    /// Linear vertex colour for a world height and a surface normal.
+    pub fn color(&self, height: f32, normal: Vec3, height_scale: f32) -> [f32; 4] {
+        let g = height / height_scale * 2.;
+        let slope = normal
+            .normalize_or(Vec3::Y)
+            .y
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees();
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches(g, slope)) {
+            return rule.color;
+        }
+        self.stops
+            .iter()
+            .find(|stop| stop.when.matches(g))
+            .map_or(self.fallback, |stop| stop.color)
+    }
+
+    fn from_file(file: PaletteFile) -> Result<Self, PaletteError> {
+        let rules = file
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                Ok(SlopeRule {
+                    slope: rule.slope,
+                    height: rule.height.unwrap_or((f32::NEG_INFINITY, f32::INFINITY)),
+                    color: parse_color(&rule.color, ColorAt::Rule(i))?,
+                })
+            })
+            .collect::<Result<_, PaletteError>>()?;
+        let stops = file
+            .stops
+            .into_iter()
+            .enumerate()
+            .map(|(i, stop)| {
+                Ok(ColorStop {
+                    when: stop.when,
+                    color: parse_color(&stop.color, ColorAt::Stop(i))?,
+                })
+            })
+            .collect::<Result<_, PaletteError>>()?;
+        Self::new(
+            rules,
+            stops,
+            parse_color(&file.fallback, ColorAt::Fallback)?,
+        )
+    }
+}
+
+/// Where in the palette file a colour came from, for error messages.
+#[derive(Clone, Copy, Debug)]
+pub enum ColorAt {
+    Rule(usize),
+    Stop(usize),
+    Fallback,
+}
+
+fn parse_color(hex: &str, at: ColorAt) -> Result<[f32; 4], PaletteError> {
+    Srgba::hex(hex)
+        .map(|color| LinearRgba::from(color).to_f32_array())
+        .map_err(|_| PaletteError::Color {
+            at,
+            value: hex.to_string(),
+        })
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+    /// The colour at `at` isn't a hex colour.
+    Color {
+        at: ColorAt,
+        value: String,
+    },
+    /// Slope rule `rule` has an invalid range.
+    Rule {
+        rule: usize,
+        reason: &'static str,
+    },
+    /// Everything `stop` would match is already matched by stop `by`.
+    Unreachable {
+        stop: usize,
+        when: Threshold,
+        by: usize,
+        by_when: Threshold,
+    },
+    /// Part of `stop`'s range is already matched by stop `with`.
+    Overlap {
+        stop: usize,
+        when: Threshold,
+        with: usize,
+        with_when: Threshold,
+    },
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaletteError::Io(err) => write!(f, "{err}"),
+            PaletteError::Ron(err) => write!(f, "{err}"),
+            PaletteError::Color { at, value } => {
+                let at = match at {
+                    ColorAt::Rule(i) => format!("rule {i}"),
+                    ColorAt::Stop(i) => format!("stop {i}"),
+                    ColorAt::Fallback => "fallback".to_string(),
+                };
+                write!(f, "{at}: {value:?} is not a hex colour")
+            }
+            PaletteError::Rule { rule, reason } => write!(f, "rule {rule}: {reason}"),
+            PaletteError::Unreachable {
+                stop,
+                when,
+                by,
+                by_when,
+            } => write!(
+                f,
+                "stop {stop} ({when:?}) can never match, stop {by} ({by_when:?}) already covers it"
+            ),
+            PaletteError::Overlap {
+                stop,
+                when,
+                with,
+                with_when,
+            } => write!(
+                f,
+                "stop {stop} ({when:?}) overlaps stop {with} ({with_when:?})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<std::io::Error> for PaletteError {
+    fn from(err: std::io::Error) -> Self {
+        PaletteError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for PaletteError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        PaletteError::Ron(err)
+    }
+}
+
+#[derive(Default)]
+pub struct TerrainPaletteLoader;
+
+impl AssetLoader for TerrainPaletteLoader {
+    type Asset = TerrainPalette;
+    type Settings = ();
+    type Error = PaletteError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        TerrainPalette::from_file(ron::de::from_bytes(&bytes)?)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["palette.ron"]
+    }
+}
+
+pub struct TerrainPalettePlugin;
+
+impl Plugin for TerrainPalettePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<TerrainPalette>()
+            .init_asset_loader::<TerrainPaletteLoader>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [f32; 4] = [1.0; 4];
+
+    fn stops(thresholds: &[Threshold]) -> Vec<ColorStop> {
+        thresholds
+            .iter()
+            .map(|&when| ColorStop { when, color: WHITE })
+            .collect()
+    }
+
+    fn check(thresholds: &[Threshold]) -> Result<TerrainPalette, PaletteError> {
+        TerrainPalette::new(Vec::new(), stops(thresholds), WHITE)
+    }
+
+    #[test]
+    fn narrowing_stops_are_valid() {
+        use Threshold::*;
+        assert!(check(&[Above(0.5), Above(0.2), Below(-0.5), Below(-0.2), Above(0.0)]).is_ok());
+        assert!(check(&[]).is_ok());
+        let default = TerrainPalette::default();
+        assert!(TerrainPalette::new(default.rules, default.stops, default.fallback).is_ok());
+    }
+
+    #[test]
+    fn baseline_below_chain_is_unreachable() {
+        // the terrain's original colours: every `Below` after `Below(-0.2)`
+        // is already covered by it
+        let mut thresholds: Vec<Threshold> = TerrainPalette::default()
+            .stops
+            .iter()
+            .map(|stop| stop.when)
+            .collect();
+        let below = thresholds.len() - 1;
+        assert_eq!(thresholds[below], Threshold::Below(-0.2));
+        thresholds.extend([-0.5, -0.6, -0.7, -0.8].map(Threshold::Below));
+
+        match check(&thresholds) {
+            Err(PaletteError::Unreachable {
+                stop,
+                when,
+                by,
+                by_when,
+            }) => {
+                assert_eq!((stop, when), (below + 1, Threshold::Below(-0.5)));
+                assert_eq!((by, by_when), (below, Threshold::Below(-0.2)));
+            }
+            other => panic!("expected Unreachable, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rising_above_is_unreachable() {
+        use Threshold::*;
+        assert!(matches!(
+            check(&[Above(0.2), Above(0.5)]),
+            Err(PaletteError::Unreachable { stop: 1, by: 0, .. })
+        ));
+        assert!(matches!(
+            check(&[Above(0.2), Above(0.2)]),
+            Err(PaletteError::Unreachable { stop: 1, by: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn crossing_ranges_overlap() {
+        use Threshold::*;
+        assert!(matches!(
+            check(&[Above(0.2), Below(0.5)]),
+            Err(PaletteError::Overlap {
+                stop: 1,
+                with: 0,
+                ..
+            })
+        ));
+        assert!(matches!(
+            check(&[Below(-0.2), Above(0.3), Above(-0.5)]),
+            Err(PaletteError::Overlap {
+                stop: 2,
+                with: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn inverted_rule_ranges_are_rejected() {
+        let rule = |slope, height| SlopeRule {
+            slope,
+            height,
+            color: WHITE,
+        };
+        for rule in [
+            rule((20.0, 10.0), (0.0, 1.0)),
+            rule((0.0, 95.0), (0.0, 1.0)),
+        ] {
+            assert!(matches!(
+                TerrainPalette::new(vec![rule], Vec::new(), WHITE),
+                Err(PaletteError::Rule { rule: 0, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn default_matches_the_asset() {
+        let text = std::fs::read_to_string(format!("assets/{TERRAIN_PALETTE_PATH}")).unwrap();
+        let file = TerrainPalette::from_file(ron::from_str(&text).unwrap()).unwrap();
+        let default = TerrainPalette::default();
+
+        assert_eq!(file.rules.len(), default.rules.len());
+        for (file, default) in file.rules.iter().zip(&default.rules) {
+            assert_eq!(file.slope, default.slope);
+            assert_eq!(file.height, default.height);
+            assert_eq!(file.color, default.color);
+        }
+        assert_eq!(file.stops.len(), default.stops.len());
+        for (file, default) in file.stops.iter().zip(&default.stops) {
+            assert_eq!(file.when, default.when);
+            assert_eq!(file.color, default.color);
+        }
+        assert_eq!(file.fallback, default.fallback);
+    }
+}
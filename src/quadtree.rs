@@ -0,0 +1,400 @@
+use std::sync::Arc;
+
+use bevy::pbr::{
+    ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline,
+};
+use bevy::prelude::*;
+use bevy::render::mesh::{MeshVertexAttribute, MeshVertexBufferLayoutRef, VertexAttributeValues};
+use bevy::render::render_resource::{
+    AsBindGroup, RenderPipelineDescriptor, ShaderRef, SpecializedMeshPipelineError, VertexFormat,
+};
+use serde::Deserialize;
+
+use crate::biome::TerrainColoring;
+use crate::heightfield::TerrainRegion;
+use crate::normals::{self, ShadingMode};
+use crate::terrain_settings::TerrainSettings;
+use crate::{color_chunk, TERRAIN_CHUNK_H, TERRAIN_CHUNK_W};
+
+/// How the terrain is split into meshes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum TerrainMode {
+    /// One `Plane3d` mesh per chunk, each at its own LOD level.
+    #[default]
+    Chunks,
+    /// A CDLOD quadtree over the whole chunk grid: nodes split and merge by
+    /// camera distance, and vertices morph towards the next coarser level
+    /// in the vertex shader before a node merges, so levels change without
+    /// popping. Fixed grids only (streaming is ignored), each patch meshed
+    /// once on the main thread with heightfield normals; `lod` settings
+    /// don't apply. Shadows see the patches unmorphed.
+    Quadtree,
+    /// Nested rings of fixed-size grids centred on the camera, each twice
+    /// as coarse as the one inside it, sampled straight from the surface
+    /// (no erosion) and refreshed only where they move onto new ground.
+    /// Reaches much further than the chunk grid; `lod` and streaming
+    /// settings don't apply.
+    Clipmap,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct QuadtreeSettings {
+    /// Levels below the root, which covers the whole grid; at most
+    /// `MAX_LEVELS`.
+    pub levels: usize,
+    /// Quads along each node edge, at every level. Rounded up to a multiple
+    /// of four, so every other vertex can morph onto the coarser grid even
+    /// in a node drawn as quarters.
+    pub grid: u32,
+    /// Camera distance within which leaf nodes are used; each coarser level
+    /// reaches twice as far as the one below.
+    pub leaf_range: f32,
+    /// Fraction of each level's range, at its far end, over which vertices
+    /// morph into the next coarser level.
+    pub morph: f32,
+}
+
+impl QuadtreeSettings {
+    /// Most `levels` the node sizes and ranges, which double per level,
+    /// are computed for.
+    pub const MAX_LEVELS: usize = 16;
+}
+
+impl Default for QuadtreeSettings {
+    fn default() -> Self {
+        Self {
+            levels: 3,
+            grid: 16,
+            leaf_range: 600.0,
+            morph: 0.3,
+        }
+    }
+}
+
+const QUADTREE_SHADER_PATH: &str = "quadtree_material.wgsl";
+
+/// Where a patch vertex ends up once fully morphed into the next coarser
+/// level.
+pub const ATTRIBUTE_MORPH_POSITION: MeshVertexAttribute =
+    MeshVertexAttribute::new("Morph_Position", 417_932_805, VertexFormat::Float32x3);
+/// A patch vertex's normal once fully morphed.
+pub const ATTRIBUTE_MORPH_NORMAL: MeshVertexAttribute =
+    MeshVertexAttribute::new("Morph_Normal", 417_932_806, VertexFormat::Float32x3);
+/// A patch vertex's colour once fully morphed.
+pub const ATTRIBUTE_MORPH_COLOR: MeshVertexAttribute =
+    MeshVertexAttribute::new("Morph_Color", 417_932_807, VertexFormat::Float32x4);
+
+/// Blends a patch's vertices towards their `ATTRIBUTE_MORPH_*` values by
+/// camera distance in the vertex shader, so patches don't need re-meshing
+/// as the camera moves.
+#[derive(Asset, AsBindGroup, Reflect, Debug, Clone)]
+pub struct MorphExtension {
+    // bindings 0-99 belong to the base material
+    /// Camera distances the morph starts and ends at, in x and y; both zero
+    /// for patches that don't morph.
+    #[uniform(100)]
+    pub range: Vec4,
+    /// World position of the camera the morph follows, in xyz.
+    #[uniform(101)]
+    pub camera: Vec4,
+}
+
+impl MaterialExtension for MorphExtension {
+    fn vertex_shader() -> ShaderRef {
+        QUADTREE_SHADER_PATH.into()
+    }
+
+    fn specialize(
+        _pipeline: &MaterialExtensionPipeline,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayoutRef,
+        _key: MaterialExtensionKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        // past every location the mesh and prepass pipelines use, which
+        // leave the extra attributes alone
+        let morph = layout.0.get_layout(&[
+            ATTRIBUTE_MORPH_POSITION.at_shader_location(10),
+            ATTRIBUTE_MORPH_NORMAL.at_shader_location(11),
+            ATTRIBUTE_MORPH_COLOR.at_shader_location(12),
+        ])?;
+        descriptor.vertex.buffers[0]
+            .attributes
+            .extend(morph.attributes);
+        Ok(())
+    }
+}
+
+pub type QuadtreeMaterial = ExtendedMaterial<StandardMaterial, MorphExtension>;
+
+/// A selected patch of terrain. Usually a whole node, meshed at its own
+/// level; when only some of a node's children are close enough to split,
+/// the others are drawn as quarters of it, at `lod` one above their `level`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeKey {
+    /// Size level of the patch: 0 is a leaf.
+    pub level: usize,
+    pub x: u32,
+    pub z: u32,
+    /// Level whose range, resolution and morph the patch uses.
+    pub lod: usize,
+}
+
+/// The quadtree over an eroded grid region. Heights come from the region,
+/// so both terrain modes show the same ground.
+pub struct Quadtree {
+    settings: QuadtreeSettings,
+    region: Arc<TerrainRegion>,
+    /// World (x, z) of the root's corner at the lowest x and z.
+    origin: Vec2,
+    size: f32,
+    /// Lowest and highest height in the region, for node bounds.
+    heights: (f32, f32),
+}
+
+impl Quadtree {
+    pub fn new(settings: &QuadtreeSettings, region: Arc<TerrainRegion>) -> Self {
+        let chunks = (region.max_chunk - region.min_chunk + IVec2::ONE).as_vec2();
+        let heights = region
+            .heights
+            .heights
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), h| {
+                (low.min(*h), high.max(*h))
+            });
+        Self {
+            settings: QuadtreeSettings {
+                grid: settings.grid.max(4).next_multiple_of(4),
+                ..settings.clone()
+            },
+            origin: region.world_min().as_vec2(),
+            size: (chunks * Vec2::new(TERRAIN_CHUNK_W, TERRAIN_CHUNK_H)).max_element(),
+            region,
+            heights,
+        }
+    }
+
+    /// The region the patches are meshed from.
+    pub fn region(&self) -> &TerrainRegion {
+        &self.region
+    }
+
+    /// World (x, z) of the root's centre and its edge length.
+    pub fn extent(&self) -> (Vec2, f32) {
+        (self.origin + Vec2::splat(self.size / 2.0), self.size)
+    }
+
+    fn root(&self) -> usize {
+        self.settings.levels
+    }
+
+    fn node_size(&self, level: usize) -> f32 {
+        self.size / (1 << (self.root() - level)) as f32
+    }
+
+    fn range(&self, lod: usize) -> f32 {
+        self.settings.leaf_range * (1 << lod) as f32
+    }
+
+    /// Distances over which `lod`'s vertices morph into the next level;
+    /// the root has nothing coarser to morph into.
+    fn morph_range(&self, lod: usize) -> Option<(f32, f32)> {
+        if lod >= self.root() {
+            return None;
+        }
+        let end = self.range(lod);
+        let previous = if lod == 0 { 0.0 } else { self.range(lod - 1) };
+        Some((end - (end - previous) * self.settings.morph, end))
+    }
+
+    fn bounds(&self, level: usize, x: u32, z: u32) -> (Vec3, Vec3) {
+        let size = self.node_size(level);
+        let min = self.origin + Vec2::new(x as f32, z as f32) * size;
+        (
+            Vec3::new(min.x, self.heights.0, min.y),
+            Vec3::new(min.x + size, self.heights.1, min.y + size),
+        )
+    }
+
+    /// Distances from `camera` to the nearest and farthest points of a
+    /// node's bounds.
+    fn distances(&self, level: usize, x: u32, z: u32, camera: Vec3) -> (f32, f32) {
+        let (min, max) = self.bounds(level, x, z);
+        let near = camera.clamp(min, max).distance(camera);
+        let far = (camera - min).abs().max((camera - max).abs()).length();
+        (near, far)
+    }
+
+    /// The patches to draw for a camera at `camera`.
+    pub fn select(&self, camera: Vec3) -> Vec<NodeKey> {
+        let mut selected = Vec::new();
+        self.select_node(self.root(), 0, 0, camera, &mut selected);
+        selected
+    }
+
+    fn select_node(
+        &self,
+        level: usize,
+        x: u32,
+        z: u32,
+        camera: Vec3,
+        selected: &mut Vec<NodeKey>,
+    ) -> bool {
+        let (near, _) = self.distances(level, x, z, camera);
+        // the root is always drawn, however far away
+        if level < self.root() && near > self.range(level) {
+            return false;
+        }
+        let whole = NodeKey {
+            level,
+            x,
+            z,
+            lod: level,
+        };
+        if level == 0 || near > self.range(level - 1) {
+            selected.push(whole);
+            return true;
+        }
+        for (dx, dz) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let (cx, cz) = (x * 2 + dx, z * 2 + dz);
+            if !self.select_node(level - 1, cx, cz, camera, selected) {
+                selected.push(NodeKey {
+                    level: level - 1,
+                    x: cx,
+                    z: cz,
+                    lod: level,
+                });
+            }
+        }
+        true
+    }
+
+    /// Levels a patch can be drawn at, from leaves up to the root.
+    pub fn lods(&self) -> usize {
+        self.root() + 1
+    }
+
+    /// Material for the patches drawn at `lod`, morphing for a camera at
+    /// `camera`.
+    pub fn material(&self, lod: usize, camera: Vec3) -> QuadtreeMaterial {
+        ExtendedMaterial {
+            base: StandardMaterial {
+                base_color: Color::WHITE,
+                perceptual_roughness: 0.9,
+                ..default()
+            },
+            extension: MorphExtension {
+                // the root has nothing coarser to morph into
+                range: self
+                    .morph_range(lod)
+                    .map_or(Vec4::ZERO, |(start, end)| Vec4::new(start, end, 0.0, 0.0)),
+                camera: camera.extend(0.0),
+            },
+        }
+    }
+
+    /// Mesh of a patch, carrying each vertex's position, normal and colour
+    /// once fully morphed into the next coarser level alongside its own,
+    /// for the vertex shader to blend between by camera distance.
+    pub fn mesh(
+        &self,
+        key: NodeKey,
+        settings: &TerrainSettings,
+        coloring: &TerrainColoring,
+    ) -> Mesh {
+        let mut mesh = self.patch(key, settings, coloring, false);
+        let mut morphed = self.patch(key, settings, coloring, self.morph_range(key.lod).is_some());
+        let (
+            Some(VertexAttributeValues::Float32x3(positions)),
+            Some(VertexAttributeValues::Float32x3(mut normals)),
+            Some(VertexAttributeValues::Float32x4(mut colors)),
+            Some(VertexAttributeValues::Float32x3(own_normals)),
+            Some(VertexAttributeValues::Float32x4(own_colors)),
+        ) = (
+            morphed.remove_attribute(Mesh::ATTRIBUTE_POSITION),
+            morphed.remove_attribute(Mesh::ATTRIBUTE_NORMAL),
+            morphed.remove_attribute(Mesh::ATTRIBUTE_COLOR),
+            mesh.attribute(Mesh::ATTRIBUTE_NORMAL),
+            mesh.attribute(Mesh::ATTRIBUTE_COLOR),
+        )
+        else {
+            return mesh;
+        };
+
+        // flat shaded triangles that collapse have no face normal, nor a
+        // colour from it; they're gone by then, so keep their own
+        for i in 0..normals.len() {
+            if !Vec3::from_array(normals[i]).is_finite() {
+                normals[i] = own_normals[i];
+                colors[i] = own_colors[i];
+            }
+        }
+        mesh.insert_attribute(ATTRIBUTE_MORPH_POSITION, positions);
+        mesh.insert_attribute(ATTRIBUTE_MORPH_NORMAL, normals);
+        mesh.insert_attribute(ATTRIBUTE_MORPH_COLOR, colors);
+        mesh
+    }
+
+    /// Mesh of a patch as drawn at its own level, or with its odd vertices
+    /// moved onto their even neighbours if `morphed`, where the coarser
+    /// level has its vertices.
+    fn patch(
+        &self,
+        key: NodeKey,
+        settings: &TerrainSettings,
+        coloring: &TerrainColoring,
+        morphed: bool,
+    ) -> Mesh {
+        let size = self.node_size(key.level);
+        let quads = self.settings.grid >> (key.lod - key.level);
+        let spacing = size / quads as f32;
+        let min = self.origin + Vec2::new(key.x as f32, key.z as f32) * size;
+
+        let vertices = quads as usize + 1;
+        let samples: Vec<Vec2> = (0..vertices * vertices)
+            .map(|i| {
+                let grid = Vec2::new((i % vertices) as f32, (i / vertices) as f32);
+                let odd = if morphed {
+                    (grid / 2.0).fract() * 2.0
+                } else {
+                    Vec2::ZERO
+                };
+                self.region_sample(min + (grid - odd) * spacing)
+            })
+            .collect();
+
+        let mut mesh = Mesh::from(
+            Plane3d::default()
+                .mesh()
+                .size(size, size)
+                .subdivisions(quads - 1),
+        );
+        if let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+        {
+            for (pos, sample) in positions.iter_mut().zip(&samples) {
+                let world = self.region.world_position_at(*sample);
+                *pos = [
+                    world.x as f32,
+                    self.region.heights.bilinear(*sample),
+                    world.y as f32,
+                ];
+            }
+        }
+
+        match settings.shading {
+            ShadingMode::Flat => normals::flat_shade(&mut mesh),
+            ShadingMode::Smooth => mesh.insert_attribute(
+                Mesh::ATTRIBUTE_NORMAL,
+                normals::heightfield_normals(&self.region, &samples),
+            ),
+        }
+        color_chunk(&mut mesh, settings, coloring);
+        mesh
+    }
+
+    /// Region sample coordinates at world `(x, z)`.
+    fn region_sample(&self, world: Vec2) -> Vec2 {
+        self.region.world_to_sample(world)
+    }
+}
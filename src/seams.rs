@@ -0,0 +1,139 @@
+use bevy::prelude::*;
+use bevy::render::mesh::VertexAttributeValues;
+use serde::Deserialize;
+
+/// Neighbouring chunks in `ChunkDetail::neighbours` order: the chunk edges
+/// at the lowest x, highest x, lowest z and highest z.
+pub const NEIGHBOURS: [IVec2; 4] = [IVec2::NEG_X, IVec2::X, IVec2::NEG_Y, IVec2::Y];
+
+/// How the T-junction cracks between chunks at different LOD levels are
+/// hidden.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub enum SeamFix {
+    /// Leave the cracks.
+    None,
+    /// A strip hanging `depth` world units down from every chunk edge fills
+    /// any gap behind it. Costs extra vertices and triangles on every edge
+    /// whether it's needed or not, and shows where a crack would have been
+    /// when seen at a grazing angle.
+    Skirts { depth: f32 },
+    /// Edge vertices of the finer chunk snap to the nearest vertex of its
+    /// coarser neighbour's edge, so both edges are the same polyline. No
+    /// extra geometry, but some triangles along the edge collapse to
+    /// nothing, and a chunk has to be re-meshed whenever a neighbour changes
+    /// level.
+    #[default]
+    Stitch,
+}
+
+/// Geometry spent on seams in one chunk mesh.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SeamCost {
+    /// Vertices added for skirts.
+    pub vertices: usize,
+    /// Triangles added for skirts, or collapsed to zero area by stitching.
+    pub triangles: usize,
+}
+
+/// Snaps the edge samples of a `vertices` x `vertices` row-major grid onto
+/// coarser neighbouring edges. `edges` holds each edge's vertex count in
+/// `NEIGHBOURS` order; edges at least as fine as the grid are left alone.
+pub fn stitch(samples: &mut [Vec2], vertices: usize, edges: [usize; 4]) {
+    let last = vertices - 1;
+    for (side, edge_vertices) in edges.into_iter().enumerate() {
+        if edge_vertices >= vertices {
+            continue;
+        }
+        // grid index of the `i`th vertex along this edge
+        let index = |i: usize| match side {
+            0 => i * vertices,
+            1 => i * vertices + last,
+            2 => i,
+            _ => last * vertices + i,
+        };
+        let (start, end) = (samples[index(0)], samples[index(last)]);
+        let coarse = (edge_vertices - 1) as f32;
+        for i in 1..last {
+            let t = (i as f32 / last as f32 * coarse).round() / coarse;
+            samples[index(i)] = start.lerp(end, t);
+        }
+    }
+}
+
+/// Triangles of `mesh` with two or more corners at the same position. A
+/// mesh without indices, as flat shading leaves it, is read as a list of
+/// triangles.
+pub fn collapsed_triangles(mesh: &Mesh) -> usize {
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        return 0;
+    };
+    let indices: Vec<usize> = match mesh.indices() {
+        Some(indices) => indices.iter().collect(),
+        None => (0..positions.len()).collect(),
+    };
+    indices
+        .chunks_exact(3)
+        .filter(|triangle| {
+            let [a, b, c] = [0, 1, 2].map(|corner| positions[triangle[corner]]);
+            a == b || b == c || c == a
+        })
+        .count()
+}
+
+/// Appends a strip hanging `depth` below the outline of a `vertices` x
+/// `vertices` row-major grid mesh, facing outwards. Skirt vertices copy
+/// every attribute of the edge vertex above them except the height, so
+/// they share its normal.
+pub fn add_skirts(mesh: &mut Mesh, vertices: usize, depth: f32) -> SeamCost {
+    let last = vertices - 1;
+    // the outline, in the direction that makes the skirts face outwards
+    let outline: Vec<usize> = (0..last)
+        .chain((0..last).map(|z| z * vertices + last))
+        .chain((1..=last).rev().map(|x| last * vertices + x))
+        .chain((1..=last).rev().map(|z| z * vertices))
+        .collect();
+
+    let first_skirt = mesh.count_vertices();
+    for (id, values) in mesh.attributes_mut() {
+        let lower = id.id == Mesh::ATTRIBUTE_POSITION.id;
+        match values {
+            VertexAttributeValues::Float32x2(values) => {
+                let skirt: Vec<_> = outline.iter().map(|&i| values[i]).collect();
+                values.extend(skirt);
+            }
+            VertexAttributeValues::Float32x3(values) => {
+                let skirt: Vec<_> = outline
+                    .iter()
+                    .map(|&i| {
+                        let [x, y, z] = values[i];
+                        [x, if lower { y - depth } else { y }, z]
+                    })
+                    .collect();
+                values.extend(skirt);
+            }
+            VertexAttributeValues::Float32x4(values) => {
+                let skirt: Vec<_> = outline.iter().map(|&i| values[i]).collect();
+                values.extend(skirt);
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(indices) = mesh.indices_mut() {
+        for (i, &top) in outline.iter().enumerate() {
+            let next = (i + 1) % outline.len();
+            let (top, next_top) = (top as u32, outline[next] as u32);
+            let (bottom, next_bottom) = ((first_skirt + i) as u32, (first_skirt + next) as u32);
+            for index in [top, next_top, bottom, next_top, next_bottom, bottom] {
+                indices.push(index);
+            }
+        }
+    }
+
+    SeamCost {
+        vertices: outline.len(),
+        triangles: outline.len() * 2,
+    }
+}
@@ -0,0 +1,132 @@
+use bevy::input::mouse::AccumulatedMouseMotion;
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::height_query::TerrainHeightQuery;
+use crate::{TERRAIN_CHUNK_H, TERRAIN_CHUNK_W};
+
+/// Keeps a circle of chunks loaded around the active camera instead of
+/// generating a fixed grid. Only applies to procedural terrain; imported
+/// heights always spawn their whole extent.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Streaming {
+    pub enabled: bool,
+    /// Chunks within this many chunk widths of the camera's chunk are loaded.
+    /// Chunks are unloaded one chunk further out, so crossing a border back
+    /// and forth doesn't regenerate anything.
+    pub radius: f32,
+}
+
+impl Default for Streaming {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            radius: 4.0,
+        }
+    }
+}
+
+impl Streaming {
+    /// Chunks that should be loaded around `centre` and aren't yet, nearest
+    /// first.
+    pub fn chunks_to_load(&self, centre: IVec2, loaded: impl Fn(IVec2) -> bool) -> Vec<IVec2> {
+        let reach = self.radius.ceil() as i32;
+        let mut missing: Vec<IVec2> = (-reach..=reach)
+            .flat_map(|z| (-reach..=reach).map(move |x| IVec2::new(x, z)))
+            .filter(|offset| offset.as_vec2().length() <= self.radius)
+            .map(|offset| centre + offset)
+            .filter(|chunk| !loaded(*chunk))
+            .collect();
+        missing.sort_by_key(|chunk| (*chunk - centre).length_squared());
+        missing
+    }
+
+    pub fn should_unload(&self, centre: IVec2, chunk: IVec2) -> bool {
+        (chunk - centre).as_vec2().length() > self.radius + 1.0
+    }
+}
+
+/// Chunk whose centre is closest to world `position`.
+pub fn chunk_at(position: Vec3) -> IVec2 {
+    IVec2::new(
+        (position.x / TERRAIN_CHUNK_W).round() as i32,
+        (position.z / TERRAIN_CHUNK_H).round() as i32,
+    )
+}
+
+/// World position of `chunk`'s centre at sea level.
+pub fn chunk_centre(chunk: IVec2) -> Vec3 {
+    Vec3::new(
+        chunk.x as f32 * TERRAIN_CHUNK_W,
+        0.0,
+        chunk.y as f32 * TERRAIN_CHUNK_H,
+    )
+}
+
+/// WASD to move, Q/E down/up, Shift to go faster, hold the right mouse
+/// button to look around. The camera can't go below the ground.
+#[derive(Component)]
+pub struct FlyCamera {
+    /// World units per second.
+    pub speed: f32,
+    /// Radians per pixel of mouse movement.
+    pub sensitivity: f32,
+    /// Least height above the ground.
+    pub clearance: f32,
+}
+
+impl Default for FlyCamera {
+    fn default() -> Self {
+        Self {
+            speed: 200.0,
+            sensitivity: 0.003,
+            clearance: 5.0,
+        }
+    }
+}
+
+pub fn fly_camera(
+    time: Res<Time>,
+    keys: Res<ButtonInput<KeyCode>>,
+    buttons: Res<ButtonInput<MouseButton>>,
+    motion: Res<AccumulatedMouseMotion>,
+    ground: TerrainHeightQuery,
+    mut cameras: Query<(&mut Transform, &FlyCamera)>,
+) {
+    for (mut transform, camera) in &mut cameras {
+        if buttons.pressed(MouseButton::Right) && motion.delta != Vec2::ZERO {
+            let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+            let yaw = yaw - motion.delta.x * camera.sensitivity;
+            let pitch = (pitch - motion.delta.y * camera.sensitivity).clamp(-1.54, 1.54);
+            transform.rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.0);
+        }
+
+        let mut direction = Vec3::ZERO;
+        for (key, axis) in [
+            (KeyCode::KeyW, *transform.forward()),
+            (KeyCode::KeyS, *transform.back()),
+            (KeyCode::KeyA, *transform.left()),
+            (KeyCode::KeyD, *transform.right()),
+            (KeyCode::KeyE, Vec3::Y),
+            (KeyCode::KeyQ, Vec3::NEG_Y),
+        ] {
+            if keys.pressed(key) {
+                direction += axis;
+            }
+        }
+
+        let boost = if keys.pressed(KeyCode::ShiftLeft) {
+            4.0
+        } else {
+            1.0
+        };
+        transform.translation +=
+            direction.normalize_or_zero() * camera.speed * boost * time.delta_secs();
+
+        let position = transform.translation;
+        if let Some(height) = ground.height(position.x, position.z) {
+            transform.translation.y = position.y.max(height + camera.clearance);
+        }
+    }
+}
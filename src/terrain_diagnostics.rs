@@ -0,0 +1,74 @@
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, RegisterDiagnostic};
+use bevy::prelude::*;
+
+// Picked up by `LogDiagnosticsPlugin` alongside the frame time diagnostics.
+
+pub const CHUNK_GENERATION_TIME: DiagnosticPath =
+    DiagnosticPath::const_new("terrain/chunk_generation_ms");
+pub const TERRAIN_GENERATION_TIME: DiagnosticPath =
+    DiagnosticPath::const_new("terrain/total_generation_ms");
+pub const EROSION_TIME: DiagnosticPath = DiagnosticPath::const_new("terrain/erosion_ms");
+pub const THERMAL_EROSION_TIME: DiagnosticPath =
+    DiagnosticPath::const_new("terrain/thermal_erosion_ms");
+pub const NORMALS_TIME: DiagnosticPath = DiagnosticPath::const_new("terrain/normals_ms");
+/// Vertices across all terrain chunk meshes.
+pub const TERRAIN_VERTICES: DiagnosticPath = DiagnosticPath::const_new("terrain/vertices");
+/// Time from a chunk being queued to its task finishing.
+pub const CHUNK_LATENCY: DiagnosticPath = DiagnosticPath::const_new("terrain/chunk_latency_ms");
+/// Chunks waiting for a generation task slot.
+pub const QUEUED_CHUNKS: DiagnosticPath = DiagnosticPath::const_new("terrain/queued_chunks");
+/// Chunk generation tasks in flight.
+pub const CHUNK_TASKS: DiagnosticPath = DiagnosticPath::const_new("terrain/chunk_tasks");
+/// Triangles of the terrain chunks (or quadtree patches) in view, per LOD
+/// level. Levels past the last share its entry.
+pub const LOD_TRIANGLES: [DiagnosticPath; 4] = [
+    DiagnosticPath::const_new("terrain/lod0_triangles"),
+    DiagnosticPath::const_new("terrain/lod1_triangles"),
+    DiagnosticPath::const_new("terrain/lod2_triangles"),
+    DiagnosticPath::const_new("terrain/lod3_triangles"),
+];
+/// Vertices added across all loaded chunks to hide LOD cracks (skirts).
+pub const SEAM_VERTICES: DiagnosticPath = DiagnosticPath::const_new("terrain/seam_vertices");
+/// Triangles added (skirts) or collapsed to nothing (stitching) across all
+/// loaded chunks to hide LOD cracks.
+pub const SEAM_TRIANGLES: DiagnosticPath = DiagnosticPath::const_new("terrain/seam_triangles");
+/// Chunks currently spawned while streaming.
+pub const LOADED_CHUNKS: DiagnosticPath = DiagnosticPath::const_new("terrain/loaded_chunks");
+/// Vertex, index and texture bytes of the loaded terrain chunks, including
+/// the grids shared by GPU-displaced chunks.
+pub const TERRAIN_MESH_BYTES: DiagnosticPath = DiagnosticPath::const_new("terrain/mesh_bytes");
+/// Patches drawn by the quadtree terrain.
+pub const QUADTREE_NODES: DiagnosticPath = DiagnosticPath::const_new("terrain/quadtree_nodes");
+/// Time spent selecting quadtree patches and meshing new ones in a frame.
+pub const QUADTREE_TIME: DiagnosticPath = DiagnosticPath::const_new("terrain/quadtree_ms");
+/// Heights the clipmap rings sampled in a frame.
+pub const CLIPMAP_SAMPLES: DiagnosticPath = DiagnosticPath::const_new("terrain/clipmap_samples");
+/// Time spent moving and re-meshing clipmap rings in a frame.
+pub const CLIPMAP_TIME: DiagnosticPath = DiagnosticPath::const_new("terrain/clipmap_ms");
+
+pub struct TerrainDiagnosticsPlugin;
+
+impl Plugin for TerrainDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        for path in LOD_TRIANGLES {
+            app.register_diagnostic(Diagnostic::new(path));
+        }
+        app.register_diagnostic(Diagnostic::new(CHUNK_GENERATION_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(TERRAIN_GENERATION_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(EROSION_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(THERMAL_EROSION_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(NORMALS_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(TERRAIN_VERTICES))
+            .register_diagnostic(Diagnostic::new(CHUNK_LATENCY).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(QUEUED_CHUNKS))
+            .register_diagnostic(Diagnostic::new(CHUNK_TASKS))
+            .register_diagnostic(Diagnostic::new(SEAM_VERTICES))
+            .register_diagnostic(Diagnostic::new(SEAM_TRIANGLES))
+            .register_diagnostic(Diagnostic::new(LOADED_CHUNKS))
+            .register_diagnostic(Diagnostic::new(TERRAIN_MESH_BYTES))
+            .register_diagnostic(Diagnostic::new(QUADTREE_NODES))
+            .register_diagnostic(Diagnostic::new(QUADTREE_TIME).with_suffix("ms"))
+            .register_diagnostic(Diagnostic::new(CLIPMAP_SAMPLES))
+            .register_diagnostic(Diagnostic::new(CLIPMAP_TIME).with_suffix("ms"));
+    }
+}
@@ -0,0 +1,174 @@
+use bevy::asset::RenderAssetUsages;
+use bevy::pbr::{ExtendedMaterial, MaterialExtension};
+use bevy::prelude::*;
+use bevy::render::mesh::Indices;
+use bevy::render::primitives::Aabb;
+use bevy::render::render_resource::{
+    AsBindGroup, Extent3d, ShaderRef, TextureDimension, TextureFormat,
+};
+use bevy::utils::HashMap;
+use serde::Deserialize;
+
+use crate::chunk_cache::ChunkSamples;
+use crate::heightfield::TerrainRegion;
+use crate::{TERRAIN_CHUNK_H, TERRAIN_CHUNK_W};
+
+const TERRAIN_SHADER_PATH: &str = "terrain_material.wgsl";
+
+/// Where chunk vertices get their heights.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum Displacement {
+    /// Every chunk gets its own mesh with the heights, normals and colours
+    /// baked into its vertices.
+    #[default]
+    Cpu,
+    /// Every chunk at a LOD level shares one flat grid mesh, displaced in
+    /// the vertex shader from a small height texture per chunk, with
+    /// normals from the same texture and colours from a colour texture.
+    /// Smooth shading with heightfield-style normals only, and no seam
+    /// fixes, since the mesh can't be changed per chunk. Chunks don't cast
+    /// shadows, as the shadow pass would see the flat grid.
+    Gpu,
+}
+
+/// Displaces a `StandardMaterial` mesh by a height texture; the standard
+/// material's base colour texture holds the matching colours.
+#[derive(Asset, AsBindGroup, Reflect, Debug, Clone)]
+pub struct TerrainExtension {
+    // bindings 0-99 belong to the base material
+    /// World (x, z) of the first height texel, then the world units
+    /// between texels.
+    #[uniform(100)]
+    pub area: Vec4,
+    /// `R32Float` heights, read with `textureLoad` since float textures
+    /// aren't filterable everywhere.
+    #[texture(101, sample_type = "float", filterable = false)]
+    pub heights: Handle<Image>,
+}
+
+impl MaterialExtension for TerrainExtension {
+    fn vertex_shader() -> ShaderRef {
+        TERRAIN_SHADER_PATH.into()
+    }
+}
+
+pub type TerrainMaterial = ExtendedMaterial<StandardMaterial, TerrainExtension>;
+
+/// A chunk's height and colour textures, one texel per `ChunkSamples`
+/// sample: the chunk and one sample past it on every side, so the shader's
+/// edge normals match the neighbours'.
+pub struct DisplacementMaps {
+    pub heights: Image,
+    pub colors: Image,
+    /// `TerrainExtension::area`.
+    pub area: Vec4,
+    /// Lowest and highest height, for the chunk's bounding box.
+    pub range: (f32, f32),
+}
+
+impl DisplacementMaps {
+    pub fn new(samples: &ChunkSamples, chunk: IVec2) -> Self {
+        let size = ChunkSamples::SIZE;
+        let heights: Vec<u8> = samples
+            .heights
+            .iter()
+            .flat_map(|height| height.to_le_bytes())
+            .collect();
+        let colors: Vec<u8> = samples.colors.iter().flatten().copied().collect();
+        let range = samples
+            .heights
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), h| {
+                (low.min(*h), high.max(*h))
+            });
+
+        let image = |data, format| {
+            Image::new(
+                Extent3d {
+                    width: size as u32,
+                    height: size as u32,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                data,
+                format,
+                // nothing reads them back once they're uploaded
+                RenderAssetUsages::RENDER_WORLD,
+            )
+        };
+        let origin = ChunkSamples::origin(chunk);
+        let spacing = TerrainRegion::spacing();
+        Self {
+            heights: image(heights, TextureFormat::R32Float),
+            colors: image(colors, TextureFormat::Rgba8UnormSrgb),
+            area: Vec4::new(origin.x, origin.y, spacing.x, spacing.y),
+            range,
+        }
+    }
+
+    /// Bytes uploaded for both textures.
+    pub fn bytes(&self) -> usize {
+        self.heights.data.len() + self.colors.data.len()
+    }
+
+    /// Bounds of the displaced chunk around its centre, replacing the flat
+    /// grid's so the chunk isn't culled while its hills are in view.
+    pub fn aabb(&self) -> Aabb {
+        let (low, high) = self.range;
+        Aabb::from_min_max(
+            Vec3::new(-TERRAIN_CHUNK_W / 2.0, low, -TERRAIN_CHUNK_H / 2.0),
+            Vec3::new(TERRAIN_CHUNK_W / 2.0, high, TERRAIN_CHUNK_H / 2.0),
+        )
+    }
+
+    /// Spends the maps on a material for the chunk.
+    pub fn material(self, images: &mut Assets<Image>) -> TerrainMaterial {
+        ExtendedMaterial {
+            base: StandardMaterial {
+                base_color_texture: Some(images.add(self.colors)),
+                perceptual_roughness: 0.9,
+                ..default()
+            },
+            extension: TerrainExtension {
+                area: self.area,
+                heights: images.add(self.heights),
+            },
+        }
+    }
+}
+
+/// Flat chunk grids shared by every GPU-displaced chunk, by subdivisions.
+#[derive(Resource, Default)]
+pub struct SharedGrids {
+    grids: HashMap<u32, Handle<Mesh>>,
+    /// Bytes of vertex and index data across all grids.
+    pub bytes: usize,
+}
+
+impl SharedGrids {
+    pub fn get(&mut self, meshes: &mut Assets<Mesh>, subdivisions: u32) -> Handle<Mesh> {
+        let bytes = &mut self.bytes;
+        self.grids
+            .entry(subdivisions)
+            .or_insert_with(|| {
+                let grid = Mesh::from(
+                    Plane3d::default()
+                        .mesh()
+                        .size(TERRAIN_CHUNK_W, TERRAIN_CHUNK_H)
+                        .subdivisions(subdivisions),
+                );
+                *bytes += mesh_bytes(&grid);
+                meshes.add(grid)
+            })
+            .clone()
+    }
+}
+
+/// Bytes of vertex and index data in `mesh`.
+pub fn mesh_bytes(mesh: &Mesh) -> usize {
+    let indices = mesh.indices().map_or(0, |indices| match indices {
+        Indices::U16(indices) => indices.len() * 2,
+        Indices::U32(indices) => indices.len() * 4,
+    });
+    mesh.get_vertex_size() as usize * mesh.count_vertices() + indices
+}
@@ -0,0 +1,424 @@
+use std::fmt;
+use std::path::Path;
+
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, LoadContext};
+use bevy::math::DVec2;
+use bevy::prelude::*;
+use bevy::tasks::AsyncComputeTaskPool;
+use noise::{BasicMulti, MultiFractal, Perlin, Seedable};
+use serde::Deserialize;
+
+use crate::biome::Biomes;
+use crate::chunk_cache::ChunkCacheSettings;
+use crate::clipmap::ClipmapSettings;
+use crate::dem::{Dem, DemImport};
+use crate::erosion::{HydraulicErosion, ThermalErosion};
+use crate::heightmap::{Heightmap, HeightmapImport};
+use crate::lod::Lod;
+use crate::noise_stack::{derive_seed, DomainWarp, NoiseLayer, MAX_SEED};
+use crate::normals::{NormalMode, ShadingMode};
+use crate::palette::TERRAIN_PALETTE_PATH;
+use crate::quadtree::{QuadtreeSettings, TerrainMode};
+use crate::streaming::Streaming;
+use crate::terrain_material::Displacement;
+use crate::{TERRAIN_CHUNK_H, TERRAIN_CHUNK_SCALLER, TERRAIN_CHUNK_W, TERRAIN_HEIGHT};
+
+/// Config file read at startup when `TERRAIN_CONFIG` isn't set.
+pub const TERRAIN_SETTINGS_PATH: &str = "assets/terrain_settings.ron";
+/// Folder the asset server reads from; config files inside it hot-reload.
+const ASSET_FOLDER: &str = "assets/";
+
+/// Where chunk heights come from before the post-noise passes run.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub enum HeightSource {
+    /// The noise stack below, on the default 5x5 chunk grid.
+    #[default]
+    Procedural,
+    /// A heightmap image or RAW file, split into as many chunks as its
+    /// extent needs.
+    Heightmap(HeightmapImport),
+    /// SRTM `.hgt` or ESRI ASCII `.asc` elevation data, sized from its
+    /// geographic extent.
+    Dem(DemImport),
+}
+
+// Defaults match `BasicMulti::<Perlin>::default()`, so an unconfigured run
+// produces the same world as before the settings existed.
+
+#[derive(Resource, Asset, TypePath, Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TerrainSettings {
+    pub source: HeightSource,
+    /// World height of a noise value of `0.5`, so the terrain spans about
+    /// `-2 * height_scale..=2 * height_scale`.
+    pub height_scale: f32,
+    /// World units per unit of noise input; larger is smoother.
+    pub noise_scale: f64,
+    /// At most `MAX_SEED`; larger seeds are rejected.
+    pub seed: u32,
+    pub octaves: usize,
+    pub frequency: f64,
+    pub lacunarity: f64,
+    pub persistence: f64,
+    /// Detail passes applied on top of the base noise, in order.
+    pub layers: Vec<NoiseLayer>,
+    pub warp: DomainWarp,
+    pub erosion: HydraulicErosion,
+    pub thermal: ThermalErosion,
+    pub normals: NormalMode,
+    pub shading: ShadingMode,
+    pub displacement: Displacement,
+    /// `TerrainPalette` asset path, relative to the asset folder.
+    pub palette: String,
+    pub biomes: Biomes,
+    pub streaming: Streaming,
+    pub lod: Lod,
+    /// Most chunk generation tasks running at once; `0` runs one per async
+    /// compute thread.
+    pub chunk_tasks: usize,
+    pub mode: TerrainMode,
+    pub quadtree: QuadtreeSettings,
+    pub clipmap: ClipmapSettings,
+    pub cache: ChunkCacheSettings,
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        Self {
+            source: HeightSource::Procedural,
+            height_scale: TERRAIN_HEIGHT,
+            noise_scale: TERRAIN_CHUNK_SCALLER,
+            seed: BasicMulti::<Perlin>::DEFAULT_SEED,
+            octaves: BasicMulti::<Perlin>::DEFAULT_OCTAVES,
+            frequency: BasicMulti::<Perlin>::DEFAULT_FREQUENCY,
+            lacunarity: BasicMulti::<Perlin>::DEFAULT_LACUNARITY,
+            persistence: BasicMulti::<Perlin>::DEFAULT_PERSISTENCE,
+            layers: Vec::new(),
+            warp: DomainWarp::default(),
+            erosion: HydraulicErosion::default(),
+            thermal: ThermalErosion::default(),
+            normals: NormalMode::Heightfield,
+            shading: ShadingMode::Smooth,
+            displacement: Displacement::Cpu,
+            palette: TERRAIN_PALETTE_PATH.to_string(),
+            biomes: Biomes::default(),
+            streaming: Streaming::default(),
+            lod: Lod::default(),
+            chunk_tasks: 0,
+            mode: TerrainMode::Chunks,
+            quadtree: QuadtreeSettings::default(),
+            clipmap: ClipmapSettings::default(),
+            cache: ChunkCacheSettings::default(),
+        }
+    }
+}
+
+impl TerrainSettings {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, TerrainSettingsError> {
+        let text = std::fs::read_to_string(path)?;
+        ron::from_str::<Self>(&text)?.validate()
+    }
+
+    /// The settings, or an error for values the generator can't handle.
+    fn validate(self) -> Result<Self, TerrainSettingsError> {
+        if self.seed > MAX_SEED {
+            return Err(TerrainSettingsError::SeedTooLarge(self.seed));
+        }
+        let levels = [
+            (
+                "quadtree",
+                self.quadtree.levels,
+                QuadtreeSettings::MAX_LEVELS,
+            ),
+            ("clipmap", self.clipmap.levels, ClipmapSettings::MAX_LEVELS),
+        ];
+        for (section, levels, max) in levels {
+            if levels > max {
+                return Err(TerrainSettingsError::TooManyLevels {
+                    section,
+                    levels,
+                    max,
+                });
+            }
+        }
+        Ok(self)
+    }
+
+    /// `TERRAIN_CONFIG`, or `TERRAIN_SETTINGS_PATH` if present.
+    fn config_path() -> Option<String> {
+        std::env::var("TERRAIN_CONFIG").ok().or_else(|| {
+            Path::new(TERRAIN_SETTINGS_PATH)
+                .exists()
+                .then(|| TERRAIN_SETTINGS_PATH.to_string())
+        })
+    }
+
+    /// Asset path of the config file, if it lives in the asset folder and
+    /// can be watched for changes.
+    pub fn asset_path() -> Option<String> {
+        Self::config_path()?
+            .strip_prefix(ASSET_FOLDER)
+            .map(str::to_string)
+    }
+
+    /// Settings from `TERRAIN_CONFIG` (or `TERRAIN_SETTINGS_PATH` if present),
+    /// with the seed overridable via `TERRAIN_SEED=<u32>` so benchmark runs
+    /// can compare worlds without recompiling.
+    pub fn from_env() -> Self {
+        let mut settings = match Self::config_path() {
+            Some(path) => Self::load(&path).unwrap_or_else(|err| {
+                error!("failed to load terrain settings from {path:?}: {err}");
+                Self::default()
+            }),
+            None => Self::default(),
+        };
+        settings.apply_env();
+        settings
+    }
+
+    /// Applies the `TERRAIN_SEED` override, if set.
+    pub fn apply_env(&mut self) {
+        if let Ok(seed) = std::env::var("TERRAIN_SEED") {
+            match seed.parse::<u32>() {
+                Ok(seed) if seed > MAX_SEED => {
+                    warn!("ignoring TERRAIN_SEED={seed}: the largest seed is {MAX_SEED}")
+                }
+                Ok(seed) => self.seed = seed,
+                Err(err) => warn!("ignoring TERRAIN_SEED={seed:?}: {err}"),
+            }
+        }
+    }
+
+    /// Loads the imported heights for file-based sources. Failures are
+    /// logged and yield `None`, falling back to procedural terrain.
+    pub fn load_heightmap(&self) -> Option<Heightmap> {
+        match &self.source {
+            HeightSource::Procedural => None,
+            HeightSource::Heightmap(import) => Heightmap::load(import)
+                .inspect_err(|err| error!("failed to load heightmap {:?}: {err}", import.path))
+                .ok(),
+            HeightSource::Dem(import) => {
+                let dem = Dem::load(import)
+                    .inspect_err(|err| error!("failed to load DEM {:?}: {err}", import.path))
+                    .ok()?;
+
+                let (min_chunk, max_chunk) = self.chunk_range(Some(&dem.heightmap));
+                let south_east = dem
+                    .extent
+                    .offset_metres(DVec2::new(dem.extent.max.x, dem.extent.min.y))
+                    / import.metres_per_unit as f64;
+                info!(
+                    "DEM {:?}: {:.4?}..{:.4?} ({}) covers {:.0}x{:.0} world units, chunks {min_chunk}..={max_chunk}",
+                    import.path,
+                    dem.extent.min,
+                    dem.extent.max,
+                    if dem.extent.geographic { "degrees" } else { "metres" },
+                    south_east.x,
+                    south_east.y,
+                );
+                Some(dem.heightmap)
+            }
+        }
+    }
+
+    /// Whether chunks stream in around the camera rather than spawning as a
+    /// fixed grid.
+    pub fn streams(&self) -> bool {
+        self.streaming.enabled
+            && matches!(self.source, HeightSource::Procedural)
+            && self.mode == TerrainMode::Chunks
+    }
+
+    /// These settings with both erosion passes off, for streamed chunks:
+    /// each is generated as its own region, and erosion fades out to the
+    /// raw heights at every region border, so it would only ever reach the
+    /// middle of a chunk.
+    pub fn without_erosion(&self) -> Self {
+        Self {
+            erosion: HydraulicErosion {
+                iterations: 0,
+                ..self.erosion.clone()
+            },
+            thermal: ThermalErosion {
+                iterations: 0,
+                ..self.thermal.clone()
+            },
+            ..self.clone()
+        }
+    }
+
+    /// Whether chunks share flat grids displaced in the vertex shader; the
+    /// quadtree always meshes on the CPU.
+    pub fn displaces_on_gpu(&self) -> bool {
+        self.displacement == Displacement::Gpu && self.mode == TerrainMode::Chunks
+    }
+
+    pub fn chunk_task_limit(&self) -> usize {
+        match self.chunk_tasks {
+            0 => AsyncComputeTaskPool::get().thread_num().max(1),
+            limit => limit,
+        }
+    }
+
+    /// Inclusive range of chunk coordinates to generate: the default 5x5
+    /// grid, or as many chunks as an imported heightmap's extent needs.
+    pub fn chunk_range(&self, heightmap: Option<&Heightmap>) -> (IVec2, IVec2) {
+        let Some(heightmap) = heightmap else {
+            return (IVec2::splat(-2), IVec2::splat(2));
+        };
+
+        let chunks = (heightmap.extent / Vec2::new(TERRAIN_CHUNK_W, TERRAIN_CHUNK_H))
+            .ceil()
+            .as_ivec2()
+            .max(IVec2::ONE);
+        let min = -chunks / 2;
+        (min, min + chunks - IVec2::ONE)
+    }
+
+    pub fn build_noise(&self) -> BasicMulti<Perlin> {
+        self.build_noise_with_seed(self.seed)
+    }
+
+    /// `seed` is at most `MAX_SEED`, as the octaves count up from it.
+    pub fn build_noise_with_seed(&self, seed: u32) -> BasicMulti<Perlin> {
+        BasicMulti::<Perlin>::default()
+            .set_seed(derive_seed(seed, 0))
+            .set_octaves(self.octaves)
+            .set_frequency(self.frequency)
+            .set_lacunarity(self.lacunarity)
+            .set_persistence(self.persistence)
+    }
+}
+
+#[derive(Debug)]
+pub enum TerrainSettingsError {
+    Io(std::io::Error),
+    Ron(ron::error::SpannedError),
+    /// The seed is above `MAX_SEED`.
+    SeedTooLarge(u32),
+    /// `levels` of the `section` settings is above its `max`.
+    TooManyLevels {
+        section: &'static str,
+        levels: usize,
+        max: usize,
+    },
+}
+
+impl fmt::Display for TerrainSettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerrainSettingsError::Io(err) => write!(f, "{err}"),
+            TerrainSettingsError::Ron(err) => write!(f, "{err}"),
+            TerrainSettingsError::SeedTooLarge(seed) => {
+                write!(f, "seed {seed} is above the largest seed, {MAX_SEED}")
+            }
+            TerrainSettingsError::TooManyLevels {
+                section,
+                levels,
+                max,
+            } => write!(
+                f,
+                "{section}.levels is {levels}, at most {max} are supported"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TerrainSettingsError {}
+
+impl From<std::io::Error> for TerrainSettingsError {
+    fn from(err: std::io::Error) -> Self {
+        TerrainSettingsError::Io(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for TerrainSettingsError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        TerrainSettingsError::Ron(err)
+    }
+}
+
+/// Reads the same RON as `TerrainSettings::load`, so the config file can be
+/// watched by the asset server. `TERRAIN_SEED` is not applied here.
+#[derive(Default)]
+pub struct TerrainSettingsLoader;
+
+impl AssetLoader for TerrainSettingsLoader {
+    type Asset = TerrainSettings;
+    type Settings = ();
+    type Error = TerrainSettingsError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        ron::de::from_bytes::<TerrainSettings>(&bytes)?.validate()
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron"]
+    }
+}
+
+pub struct TerrainSettingsPlugin;
+
+impl Plugin for TerrainSettingsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<TerrainSettings>()
+            .init_asset_loader::<TerrainSettingsLoader>()
+            .insert_resource(TerrainSettings::from_env());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use noise::NoiseFn;
+
+    use super::*;
+
+    fn heights(settings: &TerrainSettings, seed: u32) -> Vec<f64> {
+        let noise = settings.build_noise_with_seed(seed);
+        (0..64)
+            .map(|i| noise.get([i as f64 * 0.37, i as f64 * 0.11, 0.]))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_heights() {
+        let settings = TerrainSettings::default();
+        for seed in [0, 7, MAX_SEED] {
+            assert_eq!(heights(&settings, seed), heights(&settings, seed));
+        }
+    }
+
+    #[test]
+    fn different_seeds_give_different_heights() {
+        let settings = TerrainSettings::default();
+        let seeds = [0, 1, MAX_SEED - 1, MAX_SEED];
+        for (i, a) in seeds.iter().enumerate() {
+            for b in &seeds[i + 1..] {
+                assert_ne!(
+                    heights(&settings, *a),
+                    heights(&settings, *b),
+                    "{a} and {b}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_above_the_largest_are_rejected() {
+        let settings = |seed| TerrainSettings { seed, ..default() };
+        assert!(settings(MAX_SEED).validate().is_ok());
+        assert!(matches!(
+            settings(MAX_SEED + 1).validate(),
+            Err(TerrainSettingsError::SeedTooLarge(_))
+        ));
+        assert!(settings(u32::MAX).validate().is_err());
+    }
+}